@@ -43,8 +43,10 @@ fn getfilez(dir: &Path, cb: &mut dyn FnMut(&DirEntry)) -> io::Result<()> {
 // }
 
 #[proc_macro_attribute]
-pub fn handle_read(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    
+pub fn handle_read(attr: TokenStream, item: TokenStream) -> TokenStream {
+    // `#[handle_read(optional)]` makes the function return `Option<&[u8]>` instead of panicking
+    let optional = attr.to_string() == "optional";
+
     let mut v = vec![];
     getfilez(Path::new("rootfs"), &mut |d| {
         let d: &DirEntry = d;
@@ -67,18 +69,23 @@ pub fn handle_read(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut o = format!("match path {{ ");
     for k in &v {
         o = format!(
-            "{old} {src:?} | {src2:?} => {{ include_bytes!({file:?}) }}, ",
+            "{old} {src:?} | {src2:?} => {{ {wrap}(include_bytes!({file:?})) }}, ",
             old = o,
+            wrap = if optional { "Some" } else { "" },
             src = k,
             src2 = format!("/{}", k),
             file = format!("../rootfs/{}", k)
         );
     }
-    o = format!(
-        "{} _ => panic!(\"File not found (used {{}}), have: {{}} !!!\", path, {:?}) }}",
-        o,
-        format!("{:?}", v)
-    );
+    o = if optional {
+        format!("{} _ => None }}", o)
+    } else {
+        format!(
+            "{} _ => panic!(\"File not found (used {{}}), have: {{}} !!!\", path, {:?}) }}",
+            o,
+            format!("{:?}", v)
+        )
+    };
 
     let blk = parse_str::<ExprMatch>(&o).expect(&format!("eYYY: {}", o));
     let expanded = quote! {
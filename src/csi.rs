@@ -12,18 +12,20 @@ pub fn csi(panicinfo: &PanicInfo) {
 		println!("  (addr = {:?} | name = {} | len = {:#x?})", p.0, p.1, p.2);
 	}
     println!(" ======= Processes =======");
-    for tsk in preempt::TASK_QUEUE.get() {
+    for tsk in preempt::TASKS.get().values() {
 		println!(" == bgnps ==");
-        println!(" ==> pid {}", tsk.pid);
+        println!(" ==> pid {} tid {}", tsk.pid, tsk.tid);
         for p in stack_canaries::CANARIES.get() {
-            if p.0 == tsk.rsp_ptr {
+            if p.0 + p.2 == tsk.rsp_ptr {
                 println!(" ==> rsp_ptr_name: {}", p.1);
                 break;
             }
         }
         println!(" ==> rsp_ptr: {:?}", tsk.rsp_ptr);
         println!(" ==> rsp0: {:?}", tsk.rsp0);
-        println!(" ==> break: {:?}", VirtAddr::new(tsk.program_break));
+        if let Some(a) = &tsk.aspace {
+            println!(" ==> break: {:?}", VirtAddr::new(a.get().program_break));
+        }
         println!(" ==== Regs ====");
         println!("   rbx = {:#x?}", tsk.state.rbx);
         println!("   rbp = {:#x?}", tsk.state.rbp);
@@ -34,6 +36,10 @@ pub fn csi(panicinfo: &PanicInfo) {
         println!("   rsp = {:#x?}", tsk.state.rsp);
         println!("   rip = {:#x?}", tsk.state.rip);
 		println!("   rsi = {:#x?}", tsk.state.rsi);
+        if let Some(regs) = tsk.user_regs() {
+            println!(" ==== User regs ====");
+            regs.print("  ");
+        }
 		println!(" == endps ==");
     }
     println!("~~ End CSI Report ~~");
@@ -469,11 +469,18 @@ pub fn handle_rodev_with_ext2(mut dev: Box<dyn RODev>) {
     tree(&mut dev, 2, &sup, " ".to_string());
 }
 
-pub fn traverse_fs_tree(dev: &mut Box<dyn RODev>, sb: &SuperBlock, path_elems: Vec<String>) -> u32 {
+pub fn traverse_fs_tree(
+    dev: &mut Box<dyn RODev>,
+    sb: &SuperBlock,
+    path_elems: Vec<String>,
+) -> Option<u32> {
     let mut inode = 2;
     for e in path_elems {
-        inode = *readdir(dev, inode, sb).get(&e).unwrap();
+        if !stat(dev, inode, sb).contains(Ext2InodeAttr::DIRECTORY) {
+            return None;
+        }
+        inode = *readdir(dev, inode, sb).get(&e)?;
     }
 
-    inode
+    Some(inode)
 }
@@ -0,0 +1,32 @@
+use serde_derive::*;
+
+/// Errors a syscall can fail with. Returned to userland as `-(errno)` in rax,
+/// and inside `ksvc::KSvcResult::Failure` for kernel services.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u64)]
+pub enum SysError {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EPIPE = 32,
+    ENOSYS = 38,
+    ETIMEDOUT = 110,
+}
+impl SysError {
+    pub fn as_ret(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+pub type SysResult<T> = Result<T, SysError>;
@@ -0,0 +1,43 @@
+// x87/SSE register state of tasks
+//
+// switched eagerly on every context switch rather than lazily through CR0.TS: the kernel itself is
+// built with SSE, so with TS set it would fault on its own XMM spills, even inside interrupt handlers
+use crate::prelude::*;
+
+/// An fxsave area: x87, MXCSR and XMM0-15.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+impl FpuState {
+    /// The state after fninit, with every SSE exception masked.
+    pub fn new() -> FpuState {
+        let mut s = FpuState([0; 512]);
+        s.0[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+        s.0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        s
+    }
+    pub fn fcw(&self) -> u16 {
+        u16::from_le_bytes([self.0[0], self.0[1]])
+    }
+    pub fn mxcsr(&self) -> u32 {
+        u32::from_le_bytes([self.0[24], self.0[25], self.0[26], self.0[27]])
+    }
+    pub fn save(&mut self) {
+        unsafe {
+            asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack));
+        }
+    }
+    pub fn restore(&self) {
+        unsafe {
+            asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack));
+        }
+    }
+}
+impl core::fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("FpuState")
+            .field("fcw", &self.fcw())
+            .field("mxcsr", &self.mxcsr())
+            .finish()
+    }
+}
@@ -0,0 +1,67 @@
+// futex wait/wake for user programs
+use crate::prelude::*;
+use preempt::{WaitQueue, WaitReason, WakeType};
+
+// physical address of the futex word => tasks waiting on it.
+// keying by the physical address makes futexes in shared memory work across processes
+ezy_static! { FUTEXES, BTreeMap<u64, WaitQueue>, BTreeMap::new() }
+
+fn key(addr: u64) -> SysResult<u64> {
+    if addr & 3 != 0 {
+        return Err(SysError::EINVAL);
+    }
+    // copies the page if it is copy-on-write and faults it in if it is only reserved:
+    // the waker's write would move it to a new frame and a different key
+    userland::check_user_range(addr, 4, true)?;
+    userland::current_aspace()?.get().populate(VirtAddr::new(addr));
+    memory::translate(VirtAddr::new(addr))
+        .map(|p| p.as_u64())
+        .ok_or(SysError::EFAULT)
+}
+/// Blocks while the u32 at `addr` is `expected`, until `wake` or `timeout` ticks pass (None waits
+/// forever, 0 does not block). Fails with EAGAIN if the value already changed.
+pub fn wait(addr: u64, expected: u32, timeout: Option<u64>) -> SysResult<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let k = key(addr)?;
+        let mut cur = [0u8; 4];
+        userland::copy_from_user(&mut cur, addr)?;
+        if u32::from_ne_bytes(cur) != expected {
+            return Err(SysError::EAGAIN);
+        }
+        if timeout == Some(0) {
+            return Err(SysError::ETIMEDOUT);
+        }
+        FUTEXES
+            .get()
+            .entry(k)
+            .or_insert_with(WaitQueue::new)
+            .push_current(WaitReason::Futex);
+        match preempt::park(WaitReason::Futex, timeout) {
+            WakeType::WakeFutex => Ok(0),
+            _ => {
+                if let Some(q) = FUTEXES.get().get_mut(&k) {
+                    q.remove(task().tid);
+                    if q.is_empty() {
+                        FUTEXES.get().remove(&k);
+                    }
+                }
+                Err(SysError::ETIMEDOUT)
+            }
+        }
+    })
+}
+/// Wakes up to `n` tasks waiting on `addr`, returning how many were woken.
+pub fn wake(addr: u64, n: u64) -> SysResult<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let k = key(addr)?;
+        let futexes = FUTEXES.get();
+        let woken = match futexes.get_mut(&k) {
+            Some(q) => q.wake(n, WakeType::WakeFutex),
+            None => 0,
+        };
+        if futexes.get(&k).map_or(false, |q| q.is_empty()) {
+            futexes.remove(&k);
+        }
+        Ok(woken)
+    })
+}
@@ -0,0 +1,217 @@
+// handles: per-process references to kernel objects, each with a set of rights
+use crate::prelude::*;
+
+/// Send messages to a channel.
+pub const RIGHT_SEND: u64 = 1 << 0;
+/// Accept and poll on a channel.
+pub const RIGHT_RECV: u64 = 1 << 1;
+/// Map shared memory.
+pub const RIGHT_MAP: u64 = 1 << 2;
+/// Give shared memory to a process.
+pub const RIGHT_GRANT: u64 = 1 << 3;
+/// Pass the handle on, in a message or with shm_grant.
+pub const RIGHT_TRANSFER: u64 = 1 << 4;
+/// Handles a thread can attach to one message.
+pub const MAX_ATTACHED: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Object {
+    /// an ipc service id, named or not
+    Channel(u64),
+    /// a shared memory object id
+    Shm(u64),
+    Process(u64),
+}
+#[derive(Debug)]
+pub struct Handle {
+    pub object: Object,
+    pub rights: u64,
+}
+impl Handle {
+    /// Wraps an object that already counts this reference (`shm::create`, `ipc::listen` and
+    /// `ipc::create_channel` start at one).
+    pub fn new(object: Object, rights: u64) -> Handle {
+        Handle { object, rights }
+    }
+    /// Another reference to the same object, with only those of its rights that are in `rights`.
+    pub fn dup(&self, rights: u64) -> Handle {
+        let h = Handle::new(self.object, self.rights & rights);
+        match h.object {
+            Object::Shm(id) => memory::shm::retain(id),
+            Object::Channel(id) if h.rights & RIGHT_RECV != 0 => ipc::retain(id),
+            _ => {}
+        }
+        h
+    }
+}
+// shared memory lives as long as some handle to it does,
+// a channel as long as some handle that can receive from it
+impl Clone for Handle {
+    fn clone(&self) -> Handle {
+        self.dup(u64::MAX)
+    }
+}
+impl Drop for Handle {
+    fn drop(&mut self) {
+        match self.object {
+            Object::Shm(id) => memory::shm::release(id),
+            Object::Channel(id) if self.rights & RIGHT_RECV != 0 => ipc::release(id),
+            _ => {}
+        }
+    }
+}
+#[derive(Clone, Debug, Default)]
+pub struct HandleTable {
+    next: u64,
+    handles: BTreeMap<u64, Handle>,
+}
+
+// pid => its handles, shared by its threads
+ezy_static! { TABLES, BTreeMap<u64, HandleTable>, BTreeMap::new() }
+// tid => handles going out with its next message
+ezy_static! { ATTACHED, BTreeMap<u64, Vec<Handle>>, BTreeMap::new() }
+// tid => handles that came with its messages and replies, not taken yet
+ezy_static! { RECEIVED, BTreeMap<u64, VecDeque<u64>>, BTreeMap::new() }
+
+/// Puts `h` in the table of `pid`, returning its number. Numbers start at 1.
+pub fn install(pid: u64, h: Handle) -> u64 {
+    let t = TABLES.get().entry(pid).or_insert_with(HandleTable::default);
+    t.next += 1;
+    t.handles.insert(t.next, h);
+    t.next
+}
+fn lookup(n: u64, rights: u64) -> SysResult<&'static Handle> {
+    let h = TABLES
+        .get()
+        .get(&task().pid)
+        .and_then(|t| t.handles.get(&n))
+        .ok_or(SysError::EBADF)?;
+    if h.rights & rights != rights {
+        return Err(SysError::EPERM);
+    }
+    Ok(h)
+}
+/// The object behind handle `n` of the current process, which must have all of `rights`.
+pub fn get(n: u64, rights: u64) -> SysResult<Object> {
+    lookup(n, rights).map(|h| h.object)
+}
+pub fn channel(n: u64, rights: u64) -> SysResult<u64> {
+    match get(n, rights)? {
+        Object::Channel(id) => Ok(id),
+        _ => Err(SysError::EBADF),
+    }
+}
+pub fn shm(n: u64, rights: u64) -> SysResult<u64> {
+    match get(n, rights)? {
+        Object::Shm(id) => Ok(id),
+        _ => Err(SysError::EBADF),
+    }
+}
+/// EPERM unless the current process has a handle to `object` with all of `rights`.
+pub fn holds(object: Object, rights: u64) -> SysResult<()> {
+    let held = TABLES.get().get(&task().pid).map_or(false, |t| {
+        t.handles
+            .values()
+            .any(|h| h.object == object && h.rights & rights == rights)
+    });
+    if !held {
+        return Err(SysError::EPERM);
+    }
+    Ok(())
+}
+/// Copies handle `n` with only those of its rights that are in `rights`.
+pub fn dup(n: u64, rights: u64) -> SysResult<u64> {
+    let h = lookup(n, 0)?.dup(rights);
+    Ok(install(task().pid, h))
+}
+pub fn close(n: u64) -> SysResult<()> {
+    lookup(n, 0)?;
+    TABLES
+        .get()
+        .get_mut(&task().pid)
+        .unwrap()
+        .handles
+        .remove(&n);
+    Ok(())
+}
+/// Moves handle `n` out of the table; it goes with the next message or reply this thread sends.
+pub fn attach(n: u64) -> SysResult<()> {
+    lookup(n, RIGHT_TRANSFER)?;
+    let attached = ATTACHED.get().entry(task().tid).or_insert_with(Vec::new);
+    if attached.len() >= MAX_ATTACHED {
+        return Err(SysError::E2BIG);
+    }
+    let t = TABLES.get().get_mut(&task().pid).unwrap();
+    attached.push(t.handles.remove(&n).unwrap());
+    Ok(())
+}
+/// Takes what the current thread attached, for the message it is sending.
+pub fn outgoing() -> Vec<Handle> {
+    ATTACHED.get().remove(&task().tid).unwrap_or_default()
+}
+/// Installs handles that came with a message for thread `tid` of `pid`.
+pub fn receive(pid: u64, tid: u64, hs: Vec<Handle>) {
+    if hs.is_empty() {
+        return;
+    }
+    let nums: Vec<u64> = hs.into_iter().map(|h| install(pid, h)).collect();
+    RECEIVED
+        .get()
+        .entry(tid)
+        .or_insert_with(VecDeque::new)
+        .extend(nums);
+}
+/// Number of the next handle that came with a message to the current thread.
+pub fn take_received() -> SysResult<u64> {
+    RECEIVED
+        .get()
+        .get_mut(&task().tid)
+        .and_then(|q| q.pop_front())
+        .ok_or(SysError::EAGAIN)
+}
+/// A handle to process `pid`: the caller itself (0) or one of its children.
+pub fn process(pid: u64) -> SysResult<u64> {
+    let me = task().pid;
+    let pid = if pid == 0 { me } else { pid };
+    if pid != me
+        && !preempt::tasks().any(|t| t.pid == pid && t.ppid == me && t.exit_code.is_none())
+    {
+        return Err(SysError::ESRCH);
+    }
+    Ok(install(
+        me,
+        Handle::new(Object::Process(pid), RIGHT_GRANT | RIGHT_TRANSFER),
+    ))
+}
+/// Copies shared memory handle `shm` into the process behind handle `to`, returning its number there.
+pub fn grant(shm: u64, to: u64) -> SysResult<u64> {
+    let pid = match get(to, RIGHT_GRANT)? {
+        Object::Process(pid) => pid,
+        _ => return Err(SysError::EBADF),
+    };
+    self::shm(shm, RIGHT_TRANSFER)?;
+    // tasks() leaves out the caller, which may be the only thread of its process
+    if pid != task().pid && !preempt::tasks().any(|t| t.pid == pid && t.exit_code.is_none()) {
+        return Err(SysError::ESRCH);
+    }
+    let h = lookup(shm, 0)?.clone();
+    Ok(install(pid, h))
+}
+/// A forked child starts with copies of its parent's handles.
+pub fn fork(parent: u64, child: u64) {
+    if let Some(t) = TABLES.get().get(&parent) {
+        let t = t.clone();
+        TABLES.get().insert(child, t);
+    }
+}
+/// Drops the handles of an exiting process and of its threads `tids`.
+pub fn exit(pid: u64, tids: &[u64]) {
+    TABLES.get().remove(&pid);
+    for tid in tids {
+        exit_thread(*tid);
+    }
+}
+pub fn exit_thread(tid: u64) {
+    ATTACHED.get().remove(&tid);
+    RECEIVED.get().remove(&tid);
+}
@@ -89,7 +89,8 @@ pub fn init(boot_info: &'static multiboot::information::Multiboot) {
         };
     });
     run_task("task_queue.init", || {
-        preempt::TASK_QUEUE.get();
+        preempt::init();
+        preempt::KERNEL_CR3.get();
     });
 
     // Set up syscalls
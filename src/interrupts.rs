@@ -1,4 +1,6 @@
+use crate::trap::{self, TrapFrame};
 use crate::{print, println};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use pic8259_simple::ChainedPics;
@@ -92,12 +94,15 @@ pub fn set_rsp0(va: VirtAddr) {
     let tss_mut = unsafe { &mut *(tss_borrow as *const TaskStateSegment as *mut TaskStateSegment) };
     tss_mut.privilege_stack_table[0] = va;
 }
+const RSP0_STACK_SIZE: usize = 4096 * 5;
 pub fn alloc_rsp0() -> VirtAddr {
-    const STACK_SIZE: usize = 4096 * 5;
-    let stack_start = VirtAddr::from_ptr(crate::memory::malloc(STACK_SIZE));
-    let stack_end = stack_start + STACK_SIZE;
+    let stack_start = VirtAddr::from_ptr(crate::memory::malloc(RSP0_STACK_SIZE));
+    let stack_end = stack_start + RSP0_STACK_SIZE;
     stack_end
 }
+pub fn free_rsp0(stack_end: VirtAddr) {
+    crate::memory::free((stack_end - RSP0_STACK_SIZE).as_mut_ptr());
+}
 
 lazy_static! {
     pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
@@ -114,24 +119,25 @@ let mut gdt = GlobalDescriptorTable::new();
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // everything but double faults goes through trap::trap_common to trap_dispatch
         unsafe {
+            idt.breakpoint.set_handler_fn(trap::handler(trap::breakpoint_entry));
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX);
-        }
-        unsafe {
             idt.page_fault
-                .set_handler_fn(page_fault_handler)
+                .set_handler_fn(trap::handler(trap::page_fault_entry))
                 .set_stack_index(PAGE_FAULT_STACK_INDEX);
+            idt.invalid_opcode.set_handler_fn(trap::handler(trap::invalid_opcode_entry));
+            idt.general_protection_fault.set_handler_fn(trap::handler(trap::gpe_entry));
+            idt[InterruptIndex::Timer.as_usize()].set_handler_fn(trap::handler(trap::timer_entry));
+            idt[InterruptIndex::Keyboard.as_usize()]
+                .set_handler_fn(trap::handler(trap::keyboard_entry));
+            idt[InterruptIndex::COM1.as_usize()].set_handler_fn(trap::handler(trap::com1_entry));
+            idt[InterruptIndex::COM2.as_usize()].set_handler_fn(trap::handler(trap::com2_entry));
+            idt[InterruptIndex::PrimaryATA.as_usize()]
+                .set_handler_fn(trap::handler(trap::primary_ata_entry));
         }
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.general_protection_fault.set_handler_fn(gpe);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_handler);
-        idt[InterruptIndex::COM1.as_usize()].set_handler_fn(com1_handler);
-        idt[InterruptIndex::COM2.as_usize()].set_handler_fn(com1_handler);
-        idt[InterruptIndex::PrimaryATA.as_usize()].set_handler_fn(com1_handler);
         unsafe { PICS.get().initialize() };
         idt
     };
@@ -154,8 +160,34 @@ pub struct Selectors {
     pub usercode: SegmentSelector,
     pub userdata: SegmentSelector,
 }
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame,);
+/// Called by `trap::trap_common` with the frame of every interrupt, exception and syscall.
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    if frame.from_user() {
+        crate::preempt::current().frame = frame as *mut TrapFrame as u64;
+    }
+    const TIMER_VECTOR: u64 = InterruptIndex::Timer as u64;
+    const KEYBOARD_VECTOR: u64 = InterruptIndex::Keyboard as u64;
+    const COM2_VECTOR: u64 = InterruptIndex::COM2 as u64;
+    const COM1_VECTOR: u64 = InterruptIndex::COM1 as u64;
+    const PRIMARY_ATA_VECTOR: u64 = InterruptIndex::PrimaryATA as u64;
+    match frame.vector {
+        3 => breakpoint_handler(frame),
+        6 => invalid_opcode_handler(frame),
+        13 => gpe(frame),
+        14 => page_fault_handler(frame),
+        TIMER_VECTOR => timer_interrupt_handler(frame),
+        KEYBOARD_VECTOR => keyboard_handler(frame),
+        COM1_VECTOR | COM2_VECTOR | PRIMARY_ATA_VECTOR => com1_handler(frame),
+        trap::SYSCALL_VECTOR => {
+            frame.rax =
+                crate::userland::syscall_handler(frame.rdi, frame.rsi, frame.rdx, frame.r10);
+        }
+        v => panic!("Unexpected trap {}:\n{:#x?}", v, frame),
+    }
+}
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
 }
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
@@ -166,51 +198,52 @@ extern "x86-interrupt" fn double_fault_handler(
         stack_frame, error_code
     );
 }
-extern "x86-interrupt" fn gpe(stack_frame: &mut InterruptStackFrame, error_code: u64) -> () {
-    panic!("#GP at: \n{:#?}\nError code: {}", stack_frame, error_code);
+fn gpe(frame: &mut TrapFrame) {
+    if frame.from_user() {
+        crate::userland::user_crash(
+            "general protection fault",
+            frame.rip,
+            None,
+            &frame.error,
+            crate::userland::EXIT_SEGV,
+        );
+    }
+    panic!("#GP at: \n{:#x?}\nError code: {}", frame, frame.error);
 }
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) -> () {
-    panic!("Invalid opcode at: \n{:#?}", stack_frame);
+fn invalid_opcode_handler(frame: &mut TrapFrame) {
+    if frame.from_user() {
+        crate::userland::user_crash(
+            "invalid opcode",
+            frame.rip,
+            None,
+            &(),
+            crate::userland::EXIT_ILL,
+        );
+    }
+    panic!("Invalid opcode at: \n{:#x?}", frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    println!("ifF: {}", x86_64::instructions::interrupts::are_enabled());
-    let addr = Cr2::read();
-    let f = crate::memory::get_flags_for(addr);
-    println!("F: {:?}", f);
-    // IF it was a COW page AND it was a write, copy and make writable (aka Copy On Write)
-    // if error_code.intersects(PageFaultErrorCode::CAUSED_BY_WRITE) {
-    //     let addr = Cr2::read();
-    //     // reverse lookup to page frame
-    //     let f = crate::memory::get_flags_for(addr);
-    //     match f {
-    //         Some(mut flags) => {
-    //             if flags.intersects(crate::memory::allocator::COW_PAGE) {
-    //                 // Yes indeed
-    //                 // We need a new page
-    //                 let pgn = crate::memory::mpage();
-    //                 // Copy the data
-    //                 unsafe { faster_rlibc::fastermemcpy(pgn, addr.as_ptr(), 4096); }
-    //                 crate::memory::MAPPER.get().unmap(Page::<Size4KiB>::containing_address(addr));
-    //                 flags.remove(crate::memory::allocator::COW_PAGE);
-    //                 crate::memory::map_to(addr, VirtAddr::from_ptr(pgn), flags);
-    //                 // Go back
-    //                 return;
-    //             }
-    //         }
-    //         None => {}
-    //     }
-    // }
-    // loop
+fn page_fault_handler(frame: &mut TrapFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error);
+    if crate::userland::handle_user_fault(Cr2::read(), error_code) {
+        return;
+    }
+    // the task never comes back to this IST frame, so it is fine to switch away on it
+    if frame.from_user() {
+        crate::userland::user_crash(
+            "page fault",
+            frame.rip,
+            Some(Cr2::read().as_u64()),
+            &error_code,
+            crate::userland::EXIT_SEGV,
+        );
+    }
     crate::io::Printer.set_color(255, 0, 0);
     println!(
-        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nWhy?: {:?}\n{:#?}",
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nWhy?: {:?}\n{:#x?}",
         Cr2::read(),
         error_code,
-        stack_frame
+        frame
     );
     if (error_code & PageFaultErrorCode::MALFORMED_TABLE) == PageFaultErrorCode::MALFORMED_TABLE {
         panic!("A malformed table was detected.");
@@ -224,25 +257,26 @@ extern "x86-interrupt" fn page_fault_handler(
     }
     if (error_code & PageFaultErrorCode::CAUSED_BY_WRITE) == PageFaultErrorCode::CAUSED_BY_WRITE {
         panic!(
-            "Error: We tried to write to an invalid address {:?} from {:?}",
+            "Error: We tried to write to an invalid address {:?} from {:#x}",
             Cr2::read(),
-            stack_frame.instruction_pointer
+            frame.rip
         );
     }
     panic!("Page fault");
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+fn timer_interrupt_handler(_frame: &mut TrapFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
     unsafe {
         PICS.get()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
     if !crate::constants::is_test() {
-        crate::preempt::yield_task();
+        crate::preempt::tick();
     }
     // println!("if: {}", x86_64::instructions::interrupts::are_enabled());
 }
-extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut InterruptStackFrame) {
+fn keyboard_handler(_frame: &mut TrapFrame) {
     x86_64::instructions::interrupts::disable();
     let keyboard = KEYBOARD.get();
     let scancode: u8 = unsafe { u8::read_from_port(0x60) };
@@ -258,7 +292,7 @@ extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut InterruptStackFram
     drop(keyboard);
     x86_64::instructions::interrupts::enable();
 }
-extern "x86-interrupt" fn com1_handler(_stack_frame: &mut InterruptStackFrame) {
+fn com1_handler(_frame: &mut TrapFrame) {
     x86_64::instructions::interrupts::disable();
     unsafe {
         PICS.get()
@@ -271,7 +305,22 @@ pub fn init_idt() {
     IDT.load();
 }
 
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TIMER_HZ: AtomicU64 = AtomicU64::new(100);
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+/// Rounded up, so a timeout never fires early.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let hz = TIMER_HZ.load(Ordering::Relaxed);
+    ms.saturating_mul(hz).saturating_add(999) / 1000
+}
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks.saturating_mul(1000) / TIMER_HZ.load(Ordering::Relaxed)
+}
 pub fn init_timer(freq: u32) {
+    TIMER_HZ.store(freq as u64, Ordering::Relaxed);
     unsafe {
         u8::write_to_port(0x43, 0x34);
         u8::write_to_port(0x40, ((1193182 / freq) & 0xff) as u8);
@@ -0,0 +1,483 @@
+// message queues between user processes
+use crate::prelude::*;
+use handle::Handle;
+use preempt::{WaitQueue, WaitReason, WakeType};
+
+/// Messages a service can have queued before senders block (or get EAGAIN).
+pub const QUEUE_LEN: usize = 64;
+/// Most entries one poll call can wait on.
+pub const POLL_MAX: u64 = 64;
+
+pub struct Message {
+    pub token: u64,
+    pub box1: Option<&'static [u8]>,
+    pub box2: Option<&'static [u8]>,
+    /// attached with handle_attach, installed for whoever takes the message
+    pub handles: Vec<Handle>,
+}
+pub struct Service {
+    /// None for channels made with channel_create, only reachable through handles
+    pub name: Option<String>,
+    pub pid: u64,
+    queue: VecDeque<Message>,
+    /// threads waiting for a message (accept, poll)
+    receivers: WaitQueue,
+    /// senders waiting for room in the queue
+    senders: WaitQueue,
+    /// handles to it with the recv right, counting ones in flight in messages.
+    /// The service goes away with the last one
+    refs: u64,
+}
+/// A message that has not been replied to yet.
+struct Pending {
+    /// the sending thread
+    tid: u64,
+    is_async: bool,
+    /// pid of the process that accepted it, 0 while it is queued
+    server: u64,
+    /// the sender timed out, the reply goes nowhere
+    cancelled: bool,
+}
+
+// service id => service
+ezy_static! { SERVICES, BTreeMap<u64, Service>, BTreeMap::new() }
+// name => service id
+ezy_static! { NAMES, BTreeMap<String, u64>, BTreeMap::new() }
+// reply token => where the reply goes
+ezy_static! { PENDING, BTreeMap<u64, Pending>, BTreeMap::new() }
+// tid => replies to its async sends
+ezy_static! { INBOX, BTreeMap<u64, VecDeque<Message>>, BTreeMap::new() }
+// threads polling their inbox
+ezy_static! { INBOX_WAITERS, BTreeSet<u64>, BTreeSet::new() }
+// threads in lookup waiting for a name to show up
+ezy_static! { NAME_WAITERS, WaitQueue, WaitQueue::new() }
+counter!(SERVICE_COUNTER);
+counter!(TOKEN_COUNTER);
+
+fn free(b: Option<&'static [u8]>) {
+    if let Some(s) = b {
+        unsafe {
+            Box::from_raw(s as *const [u8] as *mut [u8]);
+        }
+    }
+}
+/// Ticks left until `deadline`, failing with ETIMEDOUT once it has passed.
+fn remaining(deadline: Option<u64>) -> SysResult<Option<u64>> {
+    match deadline {
+        Some(d) if d <= interrupts::ticks() => Err(SysError::ETIMEDOUT),
+        Some(d) => Ok(Some(d - interrupts::ticks())),
+        None => Ok(None),
+    }
+}
+/// Hands a reply to whoever sent message `token`.
+fn deliver(
+    token: u64,
+    p: Pending,
+    box1: Option<&'static [u8]>,
+    box2: Option<&'static [u8]>,
+    handles: Vec<Handle>,
+    wake_type: WakeType,
+) {
+    if p.cancelled {
+        free(box1);
+        free(box2);
+        return;
+    }
+    if p.is_async {
+        let sender = match p.tid == task().tid {
+            true => Some(task()),
+            false => preempt::queued_task(p.tid),
+        };
+        // exit_thread already cleared its inbox, and nobody would ever take this out of it
+        if sender.map_or(true, |t| t.exit_code.is_some()) {
+            free(box1);
+            free(box2);
+            return;
+        }
+        INBOX
+            .get()
+            .entry(p.tid)
+            .or_insert_with(VecDeque::new)
+            .push_back(Message {
+                token,
+                box1,
+                box2,
+                handles,
+            });
+        if INBOX_WAITERS.get().remove(&p.tid) && preempt::waiting_for(p.tid, WaitReason::Poll)
+        {
+            preempt::unpark(p.tid, WakeType::WakeMessage, task().tid);
+        }
+        return;
+    }
+    match preempt::queued_task(p.tid).filter(|t| t.parked) {
+        Some(t) => {
+            t.box1 = box1;
+            t.box2 = box2;
+            handle::receive(t.pid, p.tid, handles);
+            preempt::unpark(p.tid, wake_type, task().tid);
+        }
+        // the sender is gone, nobody wants the reply
+        None => {
+            free(box1);
+            free(box2);
+        }
+    }
+}
+/// Answers message `token` with an error reply, like kernel services do.
+fn fail(token: u64) {
+    if let Some(p) = PENDING.get().remove(&token) {
+        let blob = postcard::to_allocvec(&ksvc::KSvcResult::Failure(SysError::EPIPE)).unwrap();
+        deliver(
+            token,
+            p,
+            Some(blob.leak()),
+            None,
+            Vec::new(),
+            WakeType::WakeServiceGone,
+        );
+    }
+}
+
+/// Withdraws message `token` after its sender gave up waiting. A queued message goes back
+/// into buf1/buf2, the reply to an accepted one is thrown away.
+fn cancel(token: u64) {
+    match PENDING.get().get_mut(&token) {
+        Some(p) if p.server != 0 => {
+            p.cancelled = true;
+            return;
+        }
+        Some(_) => {}
+        None => return,
+    }
+    PENDING.get().remove(&token);
+    for svc in SERVICES.get().values_mut() {
+        if let Some(i) = svc.queue.iter().position(|m| m.token == token) {
+            let m = svc.queue.remove(i).unwrap();
+            task().box1 = m.box1;
+            task().box2 = m.box2;
+            // back in the table, numbered like handles that came with a message
+            handle::receive(task().pid, task().tid, m.handles);
+            svc.senders.wake(1, WakeType::WakeMessage);
+            return;
+        }
+    }
+}
+
+fn new_service(name: Option<String>) -> u64 {
+    let id = SERVICE_COUNTER::inc() as u64;
+    SERVICES.get().insert(
+        id,
+        Service {
+            name,
+            pid: task().pid,
+            queue: VecDeque::new(),
+            receivers: WaitQueue::new(),
+            senders: WaitQueue::new(),
+            refs: 1,
+        },
+    );
+    id
+}
+/// Registers `name` for the current process, returning the service id.
+pub fn listen(name: String) -> SysResult<u64> {
+    if NAMES.get().contains_key(&name) {
+        return Err(SysError::EEXIST);
+    }
+    let id = new_service(Some(name.clone()));
+    NAMES.get().insert(name, id);
+    NAME_WAITERS.get().wake(u64::MAX, WakeType::WakeMessage);
+    Ok(id)
+}
+/// Makes a service without a name, owned by the current process. Like a named one, it starts
+/// with one reference, for the recv handle the caller gets.
+pub fn create_channel() -> u64 {
+    new_service(None)
+}
+/// The service registered as `name`.
+pub fn resolve(name: &str) -> SysResult<u64> {
+    NAMES.get().get(name).copied().ok_or(SysError::ENOENT)
+}
+fn remove_service(id: u64) {
+    let mut svc = SERVICES.get().remove(&id).unwrap();
+    if let Some(name) = &svc.name {
+        NAMES.get().remove(name);
+    }
+    for m in svc.queue.drain(..) {
+        free(m.box1);
+        free(m.box2);
+        fail(m.token);
+    }
+    // blocked senders find the service gone when they retry
+    svc.senders.wake(u64::MAX, WakeType::WakeServiceGone);
+    svc.receivers.wake(u64::MAX, WakeType::WakeServiceGone);
+}
+/// Drops a service the current process can receive from. Queued messages get an error reply;
+/// ones already accepted can still be answered.
+pub fn unlisten(name: &str) -> SysResult<()> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        remove_service(named(name, handle::RIGHT_RECV)?);
+        Ok(())
+    })
+}
+/// Waits until `name` is registered and returns its service id. `wait` false fails with ENOENT
+/// right away; `timeout` is in ticks, None waits forever.
+pub fn lookup(name: &str, wait: bool, timeout: Option<u64>) -> SysResult<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let deadline = timeout.map(|t| interrupts::ticks() + t);
+        loop {
+            if let Some(id) = NAMES.get().get(name) {
+                return Ok(*id);
+            }
+            if !wait {
+                return Err(SysError::ENOENT);
+            }
+            let left = remaining(deadline)?;
+            NAME_WAITERS.get().push_current(WaitReason::Lookup);
+            let w = preempt::park(WaitReason::Lookup, left);
+            NAME_WAITERS.get().remove(task().tid);
+            if w == WakeType::WakeTimeout {
+                return Err(SysError::ETIMEDOUT);
+            }
+        }
+    })
+}
+pub fn retain(id: u64) {
+    if let Some(svc) = SERVICES.get().get_mut(&id) {
+        svc.refs += 1;
+    }
+}
+/// Drops a reference; the last one takes the service down like `unlisten` does.
+/// The service may be gone already, after unlisten.
+pub fn release(id: u64) {
+    if let Some(svc) = SERVICES.get().get_mut(&id) {
+        svc.refs -= 1;
+        if svc.refs == 0 {
+            remove_service(id);
+        }
+    }
+}
+/// Cleans up after a dead process: everything it accepted but did not answer gets an error
+/// reply. `tids` are its threads. Its services go with its handles, unless it passed them on.
+pub fn exit(pid: u64, tids: &[u64]) {
+    let accepted: Vec<u64> = PENDING
+        .get()
+        .iter()
+        .filter(|(_, p)| p.server == pid)
+        .map(|(t, _)| *t)
+        .collect();
+    for token in accepted {
+        fail(token);
+    }
+    for tid in tids {
+        exit_thread(*tid);
+    }
+}
+/// Drops the inbox of a dead thread.
+pub fn exit_thread(tid: u64) {
+    INBOX_WAITERS.get().remove(&tid);
+    if let Some(q) = INBOX.get().remove(&tid) {
+        for m in q {
+            free(m.box1);
+            free(m.box2);
+        }
+    }
+}
+/// Queues buf1/buf2 and the attached handles as a message to service `id`, returning its reply
+/// token. Blocks while the queue is full, until the `deadline` tick if there is one, unless
+/// `is_async`, which fails with EAGAIN instead and gets the reply in the inbox.
+pub fn send(id: u64, is_async: bool, deadline: Option<u64>) -> SysResult<u64> {
+    let svc = loop {
+        let svc = SERVICES.get().get_mut(&id).ok_or(SysError::EPIPE)?;
+        if svc.queue.len() < QUEUE_LEN {
+            break svc;
+        }
+        if is_async {
+            return Err(SysError::EAGAIN);
+        }
+        let left = remaining(deadline)?;
+        svc.senders.push_current(WaitReason::QueueFull);
+        if preempt::park(WaitReason::QueueFull, left) == WakeType::WakeTimeout {
+            if let Some(svc) = SERVICES.get().get_mut(&id) {
+                svc.senders.remove(task().tid);
+            }
+            return Err(SysError::ETIMEDOUT);
+        }
+    };
+    let token = TOKEN_COUNTER::inc() as u64;
+    PENDING.get().insert(
+        token,
+        Pending {
+            tid: task().tid,
+            is_async,
+            server: 0,
+            cancelled: false,
+        },
+    );
+    svc.queue.push_back(Message {
+        token,
+        box1: task().box1.take(),
+        box2: task().box2.take(),
+        handles: handle::outgoing(),
+    });
+    svc.receivers.wake(1, WakeType::WakeMessage);
+    Ok(token)
+}
+/// Sends buf1/buf2 to service `id` and blocks until the reply, which replaces them.
+/// `timeout` is in ticks and covers both the wait for room in the queue and for the reply.
+pub fn call(id: u64, timeout: Option<u64>) -> SysResult<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let deadline = timeout.map(|t| interrupts::ticks() + t);
+        let token = send(id, false, deadline)?;
+        loop {
+            // send may have used up the time waiting for room in the queue
+            let left = match remaining(deadline) {
+                Ok(left) => left,
+                Err(e) => {
+                    cancel(token);
+                    return Err(e);
+                }
+            };
+            match preempt::park(WaitReason::Reply, left) {
+                WakeType::WakeResponded => return Ok(0),
+                // buf1 has the error reply
+                WakeType::WakeServiceGone => return Err(SysError::EPIPE),
+                WakeType::WakeTimeout => {
+                    cancel(token);
+                    return Err(SysError::ETIMEDOUT);
+                }
+                // someone else's wake; the reply is still coming
+                _ => {}
+            }
+        }
+    })
+}
+/// The service registered as `name`, for callers that have a handle to it with `rights`:
+/// from lookup to send, from listen to accept.
+pub fn named(name: &str, rights: u64) -> SysResult<u64> {
+    let id = resolve(name)?;
+    handle::holds(handle::Object::Channel(id), rights)?;
+    Ok(id)
+}
+/// Takes the next message for service `id` into buf1/buf2, blocking until there is one
+/// or `timeout` ticks pass. Returns its reply token, which also becomes what `respond` answers.
+pub fn accept(id: u64, timeout: Option<u64>) -> SysResult<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let deadline = timeout.map(|t| interrupts::ticks() + t);
+        loop {
+            let svc = SERVICES.get().get_mut(&id).ok_or(SysError::EPIPE)?;
+            if let Some(m) = svc.queue.pop_front() {
+                svc.senders.wake(1, WakeType::WakeMessage);
+                if let Some(p) = PENDING.get().get_mut(&m.token) {
+                    p.server = task().pid;
+                }
+                userland::freebox1();
+                userland::freebox2();
+                task().box1 = m.box1;
+                task().box2 = m.box2;
+                handle::receive(task().pid, task().tid, m.handles);
+                task().currently_responding_to = m.token;
+                return Ok(m.token);
+            }
+            let left = remaining(deadline)?;
+            svc.receivers.push_current(WaitReason::Message);
+            if preempt::park(WaitReason::Message, left) == WakeType::WakeTimeout {
+                if let Some(svc) = SERVICES.get().get_mut(&id) {
+                    svc.receivers.remove(task().tid);
+                }
+                return Err(SysError::ETIMEDOUT);
+            }
+        }
+    })
+}
+/// Answers message `token` with buf1/buf2, in any order.
+pub fn reply(token: u64) -> SysResult<()> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match PENDING.get().get(&token) {
+            Some(p) if p.server == task().pid => {}
+            _ => return Err(SysError::ENOENT),
+        }
+        let p = PENDING.get().remove(&token).unwrap();
+        let box1 = task().box1.take();
+        let box2 = task().box2.take();
+        if task().currently_responding_to == token {
+            task().currently_responding_to = 0;
+        }
+        deliver(
+            token,
+            p,
+            box1,
+            box2,
+            handle::outgoing(),
+            WakeType::WakeResponded,
+        );
+        Ok(())
+    })
+}
+/// Takes the next reply to one of our async sends into buf1/buf2, returning its token.
+pub fn recv_reply() -> SysResult<u64> {
+    let m = INBOX
+        .get()
+        .get_mut(&task().tid)
+        .and_then(|q| q.pop_front())
+        .ok_or(SysError::EAGAIN)?;
+    userland::freebox1();
+    userland::freebox2();
+    task().box1 = m.box1;
+    task().box2 = m.box2;
+    handle::receive(task().pid, task().tid, m.handles);
+    Ok(m.token)
+}
+fn ready(id: u64) -> bool {
+    match id {
+        0 => INBOX
+            .get()
+            .get(&task().tid)
+            .map_or(false, |q| !q.is_empty()),
+        id => SERVICES
+            .get()
+            .get(&id)
+            .map_or(false, |s| !s.queue.is_empty()),
+    }
+}
+/// Waits until one of `ids` has something to take and returns its index. An id is a service,
+/// or 0 for replies to the caller's async sends. `timeout` is in ticks, None waits forever.
+pub fn poll(ids: &[u64], timeout: Option<u64>) -> SysResult<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let deadline = timeout.map(|t| interrupts::ticks() + t);
+        loop {
+            // checked on every round, services can go away while we are parked
+            if ids.iter().any(|id| *id != 0 && !SERVICES.get().contains_key(id)) {
+                return Err(SysError::EPIPE);
+            }
+            if let Some(i) = ids.iter().position(|id| ready(*id)) {
+                return Ok(i as u64);
+            }
+            let left = remaining(deadline)?;
+            // nothing below can fail, so every queue joined here is left again after park
+            for id in ids {
+                match id {
+                    0 => {
+                        INBOX_WAITERS.get().insert(task().tid);
+                    }
+                    id => SERVICES
+                        .get()
+                        .get_mut(id)
+                        .unwrap()
+                        .receivers
+                        .push_current(WaitReason::Poll),
+                }
+            }
+            let w = preempt::park(WaitReason::Poll, left);
+            INBOX_WAITERS.get().remove(&task().tid);
+            for id in ids {
+                if let Some(s) = SERVICES.get().get_mut(id) {
+                    s.receivers.remove(task().tid);
+                }
+            }
+            if w == WakeType::WakeTimeout {
+                return Err(SysError::ETIMEDOUT);
+            }
+        }
+    })
+}
@@ -1,13 +1,20 @@
 use crate::{drive::RODev, prelude::*};
-use drive::gpt::GetGPTPartitions;
+use drive::{ext2::Ext2InodeAttr, gpt::GetGPTPartitions};
 use serde_derive::*;
-use x86_64::structures::paging::PageTableFlags;
-ezy_static! { KSVC_TABLE, BTreeMap<String, Box<dyn Send + Sync + Fn<(), Output = ()>>>, BTreeMap::new() }
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+ezy_static! { KSVC_TABLE, BTreeMap<String, Box<dyn Send + Sync + Fn<(), Output = SysResult<()>>>>, BTreeMap::new() }
 
 #[derive(Serialize, Deserialize)]
 pub enum KSvcResult {
     Success,
-    Failure(String),
+    Failure(SysError),
+}
+
+// exec: the argv blob in buf2, handed to the new process in buf1
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecArgs {
+    pub argv: Vec<String>,
+    pub envp: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -55,35 +62,62 @@ pub struct IOOp {
 
 // end io
 
+fn request<T: serde::de::DeserializeOwned>() -> SysResult<T> {
+    match task().box1 {
+        Some(b) => postcard::from_bytes(b).map_err(|_| SysError::EINVAL),
+        None => Err(SysError::EINVAL),
+    }
+}
+pub fn reply<T: serde::Serialize>(r: &T) {
+    userland::freebox1();
+    let x = postcard::to_allocvec(r).unwrap();
+    task().box1 = Some(x.leak());
+}
+
 pub fn ksvc_init() {
     let t = KSVC_TABLE.get();
 
     t.insert("log".to_string(), box || {
-        let d: String = postcard::from_bytes(preempt::CURRENT_TASK.box1.unwrap()).unwrap();
+        let d: String = request()?;
         print!("{}", d);
-        let x = postcard::to_allocvec(&KSvcResult::Success).unwrap();
-        preempt::CURRENT_TASK.get().box1 = Some(x.leak());
+        reply(&KSvcResult::Success);
+        Ok(())
     });
     t.insert("pmap".to_string(), box || {
-        let d: u64 = postcard::from_bytes(preempt::CURRENT_TASK.box1.unwrap()).unwrap();
+        let d: u64 = request()?;
+        // anything higher would wrap around the top of the address space
+        if d >= 0x40000000 {
+            return Err(SysError::EINVAL);
+        }
         dprint!(
             "[pmap] Mapping in {:#x?} to {:#x?}",
             d,
             d + 0xffffffffc0000000
         );
-        memory::map_to(
-            VirtAddr::from_ptr(d as *const u8),
-            VirtAddr::from_ptr((d + 0xffffffffc0000000) as *const u8),
+        let aspace = userland::current_aspace()?;
+        if aspace.get().is_mapped(VirtAddr::new(d + 0xffffffffc0000000)) {
+            return Ok(());
+        }
+        aspace.get().map_phys(
+            VirtAddr::new(d + 0xffffffffc0000000),
+            PhysAddr::new(d),
             PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
         );
+        Ok(())
     });
     t.insert("punmap".to_string(), box || {
-        let d: u64 = postcard::from_bytes(preempt::CURRENT_TASK.box1.unwrap()).unwrap();
+        let d: u64 = request()?;
         dprint!("[punmap] UnMapping {:#x?}", d);
-        memory::munmap(VirtAddr::from_ptr(d as *const u8));
+        if d < 0xFFFF800000000000 {
+            return Err(SysError::EFAULT);
+        }
+        if !userland::current_aspace()?.get().unmap(VirtAddr::new(d)) {
+            return Err(SysError::EINVAL);
+        }
+        Ok(())
     });
     t.insert("kio".to_string(), box || unsafe {
-        let d: IOOp = postcard::from_bytes(preempt::CURRENT_TASK.box1.unwrap()).unwrap();
+        let d: IOOp = request()?;
         let r: KIOOpResult = match d.data {
             IOOpData::WriteByte(b) => {
                 outb(d.port, b);
@@ -103,80 +137,71 @@ pub fn ksvc_init() {
             IOOpData::ReadDWord() => KIOOpResult::ReadResultDWord(inl(d.port)),
             IOOpData::ReadQWord() => KIOOpResult::Failure("no support :(".to_string()),
         };
-        let x = postcard::to_allocvec(&r).unwrap();
-        preempt::CURRENT_TASK.get().box1 = Some(x.leak());
+        reply(&r);
+        Ok(())
     });
     t.insert("kfs".to_string(), box || {
-        let d: (FSOp, String) = postcard::from_bytes(preempt::CURRENT_TASK.box1.unwrap()).unwrap();
-        let mut drv: Box<dyn RODev> = box drive::SickCustomDev {};
-        let gpt = drv.get_gpt_partitions(box || box drive::SickCustomDev {});
-        let tbl: Vec<Box<(dyn RODev + 'static)>> =
-            gpt.into_iter().map(|x| (box x) as Box<dyn RODev>).collect();
-        let mut gpt0 = tbl.into_iter().next().unwrap();
-
-        let path_elems: Vec<String> =
-            d.1.split('/')
-                .map(|p| p.to_string())
-                .filter(|a| a != "")
-                .collect();
-        let bytes_of_superblock: Vec<u8> =
-            vec![gpt0.read_from(2).unwrap(), gpt0.read_from(3).unwrap()]
-                .into_iter()
-                .flat_map(|f| f)
-                .collect();
-        let sb = drive::ext2::handle_super_block(bytes_of_superblock.as_slice());
-        let inode_id = drive::ext2::traverse_fs_tree(&mut gpt0, &sb, path_elems);
-
-        let r = match d.0 {
-            FSOp::Read => FSResult::Data(drive::ext2::cat(&mut gpt0, inode_id, &sb)),
-            FSOp::ReadDir => FSResult::Dirents(
-                drive::ext2::readdir(&mut gpt0, inode_id, &sb)
-                    .into_iter()
-                    .map(|k| k.0)
-                    .collect(),
-            ),
-            FSOp::Stat => FSResult::Stats(drive::ext2::stat(&mut gpt0, inode_id, &sb).bits()),
-        };
-        let x = postcard::to_allocvec(&r).unwrap();
-        preempt::CURRENT_TASK.get().box1 = Some(x.leak());
+        let d: (FSOp, String) = request()?;
+        let r = ext2_op(d.0, &d.1)?;
+        reply(&r);
+        Ok(())
     });
 }
-pub fn dofs() {
-    let d: (FSOp, String) = postcard::from_bytes(preempt::CURRENT_TASK.box1.unwrap()).unwrap();
-    if d.0 == FSOp::Read {
-        let r = FSResult::Data(userland::readfs(&d.1).to_vec());
-        let x = postcard::to_allocvec(&r).unwrap();
-        preempt::CURRENT_TASK.get().box1 = Some(x.leak());
-        return;
-    }
+fn ext2_op(op: FSOp, path: &str) -> SysResult<FSResult> {
     let mut drv: Box<dyn RODev> = box drive::SickCustomDev {};
     let gpt = drv.get_gpt_partitions(box || box drive::SickCustomDev {});
     let tbl: Vec<Box<(dyn RODev + 'static)>> =
         gpt.into_iter().map(|x| (box x) as Box<dyn RODev>).collect();
-    let mut gpt0 = tbl.into_iter().next().unwrap();
+    let mut gpt0 = tbl.into_iter().next().ok_or(SysError::EIO)?;
 
-    let path_elems: Vec<String> =
-        d.1.split('/')
-            .map(|p| p.to_string())
-            .filter(|a| a != "")
-            .collect();
-    let bytes_of_superblock: Vec<u8> = vec![gpt0.read_from(2).unwrap(), gpt0.read_from(3).unwrap()]
-        .into_iter()
-        .flat_map(|f| f)
+    let path_elems: Vec<String> = path
+        .split('/')
+        .map(|p| p.to_string())
+        .filter(|a| a != "")
         .collect();
+    let bytes_of_superblock: Vec<u8> = vec![
+        gpt0.read_from(2).map_err(|_| SysError::EIO)?,
+        gpt0.read_from(3).map_err(|_| SysError::EIO)?,
+    ]
+    .into_iter()
+    .flat_map(|f| f)
+    .collect();
     let sb = drive::ext2::handle_super_block(bytes_of_superblock.as_slice());
-    let inode_id = drive::ext2::traverse_fs_tree(&mut gpt0, &sb, path_elems);
+    let inode_id =
+        drive::ext2::traverse_fs_tree(&mut gpt0, &sb, path_elems).ok_or(SysError::ENOENT)?;
+    let attr = drive::ext2::stat(&mut gpt0, inode_id, &sb);
 
-    let r = match d.0 {
-        FSOp::Read => FSResult::Data(userland::readfs(&d.1).to_vec()),
-        FSOp::ReadDir => FSResult::Dirents(
-            drive::ext2::readdir(&mut gpt0, inode_id, &sb)
-                .into_iter()
-                .map(|k| k.0)
-                .collect(),
-        ),
-        FSOp::Stat => FSResult::Stats(drive::ext2::stat(&mut gpt0, inode_id, &sb).bits()),
+    Ok(match op {
+        FSOp::Read => {
+            if attr.contains(Ext2InodeAttr::DIRECTORY) {
+                return Err(SysError::EISDIR);
+            }
+            if !attr.contains(Ext2InodeAttr::REGULARFILE) {
+                return Err(SysError::EINVAL);
+            }
+            FSResult::Data(drive::ext2::cat(&mut gpt0, inode_id, &sb))
+        }
+        FSOp::ReadDir => {
+            if !attr.contains(Ext2InodeAttr::DIRECTORY) {
+                return Err(SysError::ENOTDIR);
+            }
+            FSResult::Dirents(
+                drive::ext2::readdir(&mut gpt0, inode_id, &sb)
+                    .into_iter()
+                    .map(|k| k.0)
+                    .collect(),
+            )
+        }
+        FSOp::Stat => FSResult::Stats(attr.bits()),
+    })
+}
+pub fn dofs() -> SysResult<()> {
+    let d: (FSOp, String) = request()?;
+    let r = if d.0 == FSOp::Read {
+        FSResult::Data(userland::try_readfs(&d.1).ok_or(SysError::ENOENT)?.to_vec())
+    } else {
+        ext2_op(d.0, &d.1)?
     };
-    let x = postcard::to_allocvec(&r).unwrap();
-    preempt::CURRENT_TASK.get().box1 = Some(x.leak());
+    reply(&r);
+    Ok(())
 }
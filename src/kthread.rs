@@ -0,0 +1,104 @@
+// kernel threads: named tasks on the kernel page tables that can be joined for their result
+use crate::prelude::*;
+use core::any::Any;
+use core::marker::PhantomData;
+use preempt::{WaitReason, WakeType};
+
+enum Slot {
+    /// still running, with the task blocked in `join` if there is one
+    Running(Option<u64>),
+    Done(Box<dyn Any + Send>),
+}
+// tid => kernel threads whose JoinHandle is still around
+ezy_static! { THREADS, BTreeMap<u64, Slot>, BTreeMap::new() }
+
+/// Owns a kernel thread's result. Dropping it detaches the thread, which then just exits.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    tid: u64,
+    result: PhantomData<T>,
+}
+impl<T: Send + 'static> JoinHandle<T> {
+    /// Its pid, which is also its tid, as `ps` shows it.
+    pub fn id(&self) -> u64 {
+        self.tid
+    }
+    pub fn is_finished(&self) -> bool {
+        matches!(THREADS.get().get(&self.tid), Some(Slot::Done(_)))
+    }
+    /// Blocks until the thread returns, and gives back what it returned.
+    pub fn join(self) -> T {
+        x86_64::instructions::interrupts::without_interrupts(|| loop {
+            if let Some(r) = self.take() {
+                return r;
+            }
+            THREADS
+                .get()
+                .insert(self.tid, Slot::Running(Some(task().tid)));
+            preempt::park(WaitReason::Join, None);
+        })
+    }
+    /// What the thread returned, or the handle back if it is still running.
+    pub fn try_join(self) -> Result<T, JoinHandle<T>> {
+        x86_64::instructions::interrupts::without_interrupts(|| match self.take() {
+            Some(r) => Ok(r),
+            None => Err(self),
+        })
+    }
+    fn take(&self) -> Option<T> {
+        if !self.is_finished() {
+            return None;
+        }
+        match THREADS.get().remove(&self.tid) {
+            Some(Slot::Done(r)) => Some(*r.downcast::<T>().unwrap()),
+            _ => unreachable!(),
+        }
+    }
+}
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            THREADS.get().remove(&self.tid);
+        });
+    }
+}
+
+/// Runs `f` in a new kernel task with its own pid from `userland::mkpid`.
+/// `ps` lists it as `kthread:<name>`.
+pub fn spawn<T, F>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let tid = userland::mkpid();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        THREADS.get().insert(tid, Slot::Running(None));
+        preempt::task_alloc(
+            move || {
+                let r = f();
+                finish(tid, Box::new(r));
+            },
+            format!("kthread:{}", name),
+            tid,
+        );
+    });
+    JoinHandle {
+        tid,
+        result: PhantomData,
+    }
+}
+/// Hands the result to the JoinHandle, if there still is one, and exits.
+fn finish(tid: u64, r: Box<dyn Any + Send>) -> ! {
+    x86_64::instructions::interrupts::disable();
+    match THREADS.get().get_mut(&tid) {
+        Some(slot) => {
+            if let Slot::Running(Some(joiner)) = *slot {
+                preempt::unpark(joiner, WakeType::WakeThreadExited, tid);
+            }
+            *slot = Slot::Done(r);
+        }
+        // exit_task does not return, so drop it here
+        None => drop(r),
+    }
+    preempt::exit_task(0);
+}
@@ -27,13 +27,20 @@ pub mod constants;
 pub mod csi;
 pub mod devices;
 pub mod drive;
+pub mod errno;
 pub mod events;
 pub mod exiting;
+pub mod fpu;
+pub mod futex;
+pub mod handle;
 pub mod init;
 pub mod interrupts;
 pub mod io;
+pub mod ipc;
 pub mod ksvc;
 pub mod ksymmap;
+pub mod kthread;
+pub mod loader;
 pub mod main;
 pub mod memory;
 pub mod pci;
@@ -46,5 +53,6 @@ pub mod shittymutex;
 pub mod stack_canaries;
 pub mod task;
 pub mod testing;
+pub mod trap;
 pub mod unwind;
 pub mod userland;
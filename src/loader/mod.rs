@@ -0,0 +1,246 @@
+// ELF loader for user programs
+use crate::prelude::*;
+use memory::aspace::{AddressSpace, Region};
+use x86_64::structures::paging::PageTableFlags;
+use xmas_elf::{
+    header::{self, Class, Machine},
+    program::{ProgramHeader, Type},
+    sections::SectionData,
+    symbol_table::{self, Entry},
+    ElfFile,
+};
+
+pub struct LoadedElf {
+    pub entry: u64,
+    /// First page after the highest segment; where sbrk starts.
+    pub program_break: u64,
+    /// Where the program headers are mapped (0 if they are not), for AT_PHDR.
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+    /// FS base for the main thread if there is a PT_TLS segment, 0 otherwise.
+    pub thread_pointer: u64,
+}
+/// Most memory the PT_LOAD segments may ask for together; all of it is mapped up front.
+pub const MAX_IMAGE_SIZE: u64 = 64 << 20;
+/// Largest PT_TLS block. It is mapped up front, and this also keeps the rounding in `load_tls`
+/// from overflowing.
+pub const MAX_TLS_SIZE: u64 = 1 << 20;
+/// Bytes reserved above the thread pointer; only the self-pointer at fs:0 is used.
+const TCB_SIZE: u64 = 64;
+
+fn page_flags(ph: &ProgramHeader) -> PageTableFlags {
+    // x86 has no way to map a page without read access, so is_read() is implied
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if ph.flags().is_write() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !ph.flags().is_execute() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Checks the header and every PT_LOAD and PT_TLS segment, without mapping anything.
+pub fn validate(data: &[u8]) -> SysResult<ElfFile> {
+    let exe = ElfFile::new(data).map_err(|_| SysError::ENOEXEC)?;
+    header::sanity_check(&exe).map_err(|_| SysError::ENOEXEC)?;
+    if exe.header.pt1.class() != Class::SixtyFour
+        || exe.header.pt2.machine().as_machine() != Machine::X86_64
+        || exe.header.pt2.type_().as_type() != header::Type::Executable
+    {
+        return Err(SysError::ENOEXEC);
+    }
+    if exe.header.pt2.entry_point() < userland::USER_BASE {
+        return Err(SysError::ENOEXEC);
+    }
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    let mut image_size: u64 = 0;
+    for ph in exe.program_iter() {
+        let ty = ph.get_type().map_err(|_| SysError::ENOEXEC)?;
+        if ty == Type::Tls
+            && (ph.file_size() > ph.mem_size()
+                || ph.mem_size() > MAX_TLS_SIZE
+                || ph.align() > 4096
+                || !ph.align().is_power_of_two() && ph.align() != 0)
+        {
+            return Err(SysError::ENOEXEC);
+        }
+        if ty != Type::Load && ty != Type::Tls {
+            continue;
+        }
+        if ph.file_size() > ph.mem_size()
+            || ph.offset().checked_add(ph.file_size()).ok_or(SysError::ENOEXEC)?
+                > data.len() as u64
+        {
+            return Err(SysError::ENOEXEC);
+        }
+        if ty == Type::Tls {
+            continue;
+        }
+        let start = ph.virtual_addr();
+        let end = start
+            .checked_add(ph.mem_size())
+            .ok_or(SysError::ENOEXEC)?;
+        // below the mmap area: image pages are not regions, so find_free would hand them out,
+        // and sbrk grows from the end of the image up to MMAP_BASE
+        if start < userland::USER_BASE || end > userland::MMAP_BASE {
+            return Err(SysError::ENOEXEC);
+        }
+        image_size = image_size.saturating_add(ph.mem_size());
+        if image_size > MAX_IMAGE_SIZE {
+            return Err(SysError::ENOEXEC);
+        }
+        for r in &ranges {
+            if start < r.1 && r.0 < end {
+                return Err(SysError::ENOEXEC);
+            }
+        }
+        ranges.push((start, end));
+    }
+    Ok(exe)
+}
+
+/// Maps a validated ELF into `aspace`. Pages are zeroed up to `mem_size`, so
+/// `.bss` starts out empty. Segments that share a page get the union of their permissions.
+pub fn load(data: &[u8], aspace: &mut AddressSpace) -> SysResult<LoadedElf> {
+    let exe = validate(data)?;
+    let mut page_flags_map: BTreeMap<u64, PageTableFlags> = BTreeMap::new();
+    for ph in exe.program_iter() {
+        if ph.get_type().unwrap() != Type::Load || ph.mem_size() == 0 {
+            continue;
+        }
+        let first = ph.virtual_addr() & !0xfff;
+        let last = (ph.virtual_addr() + ph.mem_size() - 1) & !0xfff;
+        let mut page = first;
+        let new = page_flags(&ph);
+        loop {
+            match page_flags_map.get_mut(&page) {
+                Some(flags) => {
+                    // a shared page is only NX if every segment on it is
+                    let nx = flags.contains(PageTableFlags::NO_EXECUTE)
+                        && new.contains(PageTableFlags::NO_EXECUTE);
+                    *flags |= new;
+                    flags.set(PageTableFlags::NO_EXECUTE, nx);
+                }
+                None => {
+                    page_flags_map.insert(page, new);
+                }
+            }
+            if page == last {
+                break;
+            }
+            page += 4096;
+        }
+    }
+
+    let mut backing: BTreeMap<u64, *mut u8> = BTreeMap::new();
+    let mut program_break = userland::USER_BASE;
+    for (page, flags) in &page_flags_map {
+        backing.insert(*page, aspace.map(VirtAddr::new(*page), *flags));
+        program_break = program_break.max(page + 4096);
+    }
+
+    // copy through the kernel side of each page, the user mapping may be read-only
+    for ph in exe.program_iter() {
+        if ph.get_type().unwrap() != Type::Load {
+            continue;
+        }
+        copy_to_pages(&backing, ph.virtual_addr(), file_data(data, &ph));
+    }
+    let thread_pointer = match exe
+        .program_iter()
+        .find(|ph| ph.get_type() == Ok(Type::Tls))
+    {
+        Some(ph) => load_tls(data, &ph, aspace)?,
+        None => 0,
+    };
+
+    let phoff = exe.header.pt2.ph_offset();
+    let phdr = exe
+        .program_iter()
+        .find(|ph| ph.get_type() == Ok(Type::Phdr))
+        .map(|ph| ph.virtual_addr())
+        .or_else(|| {
+            exe.program_iter()
+                .find(|ph| {
+                    ph.get_type() == Ok(Type::Load)
+                        && ph.offset() <= phoff
+                        && phoff < ph.offset() + ph.file_size()
+                })
+                .map(|ph| ph.virtual_addr() + (phoff - ph.offset()))
+        })
+        .unwrap_or(0);
+
+    Ok(LoadedElf {
+        entry: exe.header.pt2.entry_point(),
+        program_break,
+        phdr,
+        phent: exe.header.pt2.ph_entry_size() as u64,
+        phnum: exe.header.pt2.ph_count() as u64,
+        thread_pointer,
+    })
+}
+
+fn file_data<'a>(data: &'a [u8], ph: &ProgramHeader) -> &'a [u8] {
+    &data[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]
+}
+fn copy_to_pages(backing: &BTreeMap<u64, *mut u8>, va: u64, src: &[u8]) {
+    let mut copied = 0;
+    while copied < src.len() {
+        let at = va + copied as u64;
+        let in_page = (at & 0xfff) as usize;
+        let n = (4096 - in_page).min(src.len() - copied);
+        unsafe {
+            faster_rlibc::memcpy(
+                backing[&(at & !0xfff)].offset(in_page as isize),
+                src.as_ptr().offset(copied as isize),
+                n,
+            );
+        }
+        copied += n;
+    }
+}
+/// Maps the main thread's TLS block in the mmap area and returns its thread pointer.
+/// x86_64 uses variant II: the block sits right below the thread pointer and fs:0 points to itself.
+fn load_tls(data: &[u8], ph: &ProgramHeader, aspace: &mut AddressSpace) -> SysResult<u64> {
+    let align = ph.align().max(16);
+    let block = (ph.mem_size() + align - 1) & !(align - 1);
+    let len = (block + TCB_SIZE + 4095) & !0xfff;
+    let start = aspace
+        .find_free(len, userland::MMAP_BASE, userland::MMAP_END)
+        .ok_or(SysError::ENOMEM)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    aspace.add_region(Region { start, len, flags });
+    let mut backing: BTreeMap<u64, *mut u8> = BTreeMap::new();
+    for page in (start..start + len).step_by(4096) {
+        backing.insert(page, aspace.map(VirtAddr::new(page), flags));
+    }
+    let tp = start + block;
+    copy_to_pages(&backing, tp - block, file_data(data, ph));
+    copy_to_pages(&backing, tp, &tp.to_ne_bytes());
+    Ok(tp)
+}
+
+/// Looks `addr` up in the program's own symbol table, as `name+0xoff`.
+pub fn symbolize(data: &[u8], addr: u64) -> Option<String> {
+    let exe = ElfFile::new(data).ok()?;
+    let symtab = exe.find_section_by_name(".symtab")?;
+    let syms = match symtab.get_data(&exe) {
+        Ok(SectionData::SymbolTable64(syms)) => syms,
+        _ => return None,
+    };
+    let sym = syms.iter().find(|sym| {
+        sym.get_type() == Ok(symbol_table::Type::Func)
+            && sym.value() <= addr
+            && addr < sym.value() + sym.size().max(1)
+    })?;
+    Some(format!(
+        "{}+{:#x}",
+        sym.get_name(&exe).ok()?,
+        addr - sym.value()
+    ))
+}
@@ -63,7 +63,7 @@ fn panic(info: &PanicInfo) -> ! {
     x86_64::instructions::interrupts::disable();
     io::Printer.set_color(255, 0, 0);
     println!("--------------- Kernel Panic (not syncing) ---------------");
-    println!("pid: {}", preempt::CURRENT_TASK.pid);
+    println!("pid: {}", task().pid);
     println!("info: {}", info);
     println!("] stack checking...");
     stack_canaries::stk_chk();
@@ -82,33 +82,6 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
-pub fn forkp() -> (PhysFrame, Cr3Flags) {
-    // let's get my pages
-    let old_pt = crate::memory::get_l4();
-    let new_pt = crate::memory::mpage();
-    unsafe {
-        crate::faster_rlibc::fastermemcpy(
-            new_pt,
-            old_pt as *mut x86_64::structures::paging::PageTable as *const u8,
-            2048,
-        );
-        crate::faster_rlibc::fastermemset(new_pt.offset(2048), 0, 2048);
-    }
-    let flags = Cr3::read().1;
-    let q = PhysFrame::containing_address(
-        crate::memory::translate(VirtAddr::from_ptr(new_pt)).unwrap(),
-    );
-    unsafe {
-        Cr3::write(
-            PhysFrame::containing_address(
-                crate::memory::translate(VirtAddr::from_ptr(new_pt)).unwrap(),
-            ),
-            flags,
-        );
-    }
-    (q, flags)
-}
-
 struct BadMMAN;
 impl MemoryManagement for BadMMAN {
     unsafe fn paddr_to_slice(
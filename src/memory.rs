@@ -16,6 +16,8 @@ use x86_64::{PhysAddr, VirtAddr};
 // pub static PHBASE: AtomicUsize = AtomicUsize::new(0);
 
 pub mod allocator;
+pub mod aspace;
+pub mod shm;
 pub fn munmap(area: VirtAddr) {
     let u = crate::memory::get_mapper()
         .unmap(Page::<Size4KiB>::containing_address(area))
@@ -52,6 +52,7 @@ pub static ALLOCATOR: crate::shittymutex::Mutex<Heap> =
     crate::shittymutex::Mutex::new(Heap::empty());
 pub const HEAP_START: usize = 0x100000000;
 pub const HEAP_SIZE: usize = 4 * 1024;
+pub const SHARED_PAGE: PageTableFlags = PageTableFlags::BIT_9;
 pub const COW_PAGE: PageTableFlags = PageTableFlags::BIT_10;
 pub const STACK_PAGE: PageTableFlags = PageTableFlags::BIT_11;
 pub static CUR_ADDR: core::sync::atomic::AtomicU64 =
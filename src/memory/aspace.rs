@@ -0,0 +1,371 @@
+// per-process address spaces
+use crate::prelude::*;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        page_table::PageTable, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr,
+};
+use super::allocator::{COW_PAGE, SHARED_PAGE};
+
+/// Hands out heap pages as page tables and remembers them so they can be freed.
+struct TableAlloc<'a>(&'a mut Vec<u64>);
+unsafe impl FrameAllocator<Size4KiB> for TableAlloc<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let p = super::mpage();
+        self.0.push(p as u64);
+        Some(PhysFrame::containing_address(super::translate(
+            VirtAddr::from_ptr(p),
+        )?))
+    }
+}
+
+/// A range of reserved user memory (mmap, sbrk, stacks), with the page flags it gets mapped with.
+/// Pages in it are only mapped when they are first touched.
+#[derive(Debug, Copy, Clone)]
+pub struct Region {
+    pub start: u64,
+    pub len: u64,
+    pub flags: PageTableFlags,
+}
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// The page tables of a process and the user frames mapped in them.
+/// The kernel half (L4 entries 0..256) is shared with the kernel page tables, the user half
+/// is owned and torn down on drop.
+#[derive(Debug)]
+pub struct AddressSpace {
+    l4: *mut PageTable,
+    l4_frame: PhysFrame,
+    tables: Vec<u64>,
+    /// user page => kernel heap page backing it
+    frames: BTreeMap<u64, u64>,
+    /// reserved regions by start address
+    regions: BTreeMap<u64, Region>,
+    /// end of the sbrk heap, shared by all threads of the process
+    pub program_break: u64,
+}
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+pub type AddressSpaceRef = alloc::sync::Arc<Mutex<AddressSpace>>;
+
+// heap page => number of address spaces mapping it, for pages shared by fork. Unshared pages are not in here.
+ezy_static! { FRAME_REFS, BTreeMap<u64, u64>, BTreeMap::new() }
+fn share_frame(f: u64) {
+    *FRAME_REFS.get().entry(f).or_insert(1) += 1;
+}
+/// Drops one reference to `f`, freeing it when it was the last one.
+pub fn release_frame(f: u64) {
+    let refs = FRAME_REFS.get();
+    match refs.get_mut(&f) {
+        Some(n) => {
+            *n -= 1;
+            if *n == 1 {
+                refs.remove(&f);
+            }
+        }
+        None => super::fpage(f as *mut u8),
+    }
+}
+fn is_shared(f: u64) -> bool {
+    FRAME_REFS.get().contains_key(&f)
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        let l4 = super::mpage();
+        let kernel_l4: *const u8 =
+            (crate::phmem_offset!() + preempt::KERNEL_CR3.0.start_address().as_u64()).as_ptr();
+        unsafe {
+            faster_rlibc::fastermemcpy(l4, kernel_l4, 2048);
+            faster_rlibc::fastermemset(l4.offset(2048), 0, 2048);
+        }
+        AddressSpace {
+            l4: l4 as *mut PageTable,
+            l4_frame: PhysFrame::containing_address(
+                super::translate(VirtAddr::from_ptr(l4)).unwrap(),
+            ),
+            tables: vec![],
+            frames: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            program_break: 0,
+        }
+    }
+    pub fn new_ref() -> AddressSpaceRef {
+        alloc::sync::Arc::new(Mutex::new(AddressSpace::new()))
+    }
+    pub fn cr3(&self) -> PhysFrame {
+        self.l4_frame
+    }
+    pub fn activate(&self) {
+        unsafe {
+            Cr3::write(self.l4_frame, preempt::KERNEL_CR3.1);
+        }
+    }
+    fn mapper(&mut self) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(&mut *self.l4, crate::phmem_offset!()) }
+    }
+    fn map_raw(&mut self, va: VirtAddr, frame: PhysFrame, flags: PageTableFlags) {
+        let r = unsafe {
+            self.mapper().map_to_with_table_flags(
+                Page::<Size4KiB>::containing_address(va),
+                frame,
+                PageTableFlags::PRESENT | flags,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE,
+                &mut TableAlloc(&mut self.tables),
+            )
+        };
+        r.expect("map_to failed").flush();
+    }
+    /// Maps a zeroed page at `va` owned by this address space, returning the kernel side of it.
+    /// If `va` is already mapped, the existing page is returned.
+    pub fn map(&mut self, va: VirtAddr, flags: PageTableFlags) -> *mut u8 {
+        let page = va.as_u64() & !0xfff;
+        if let Some(f) = self.frames.get(&page) {
+            return *f as *mut u8;
+        }
+        let data = super::mpage();
+        unsafe {
+            faster_rlibc::fastermemset(data, 0, 4096);
+        }
+        self.map_frame(VirtAddr::new(page), data, flags);
+        data
+    }
+    /// Maps an existing kernel heap page at `va` and takes ownership of it.
+    pub fn map_frame(&mut self, va: VirtAddr, data: *mut u8, flags: PageTableFlags) {
+        let frame =
+            PhysFrame::containing_address(super::translate(VirtAddr::from_ptr(data)).unwrap());
+        self.map_raw(va, frame, flags);
+        self.frames.insert(va.as_u64() & !0xfff, data as u64);
+    }
+    /// Maps a page that other address spaces map too (shared memory), taking a reference to it.
+    /// It stays shared across fork instead of becoming copy-on-write.
+    pub fn map_shared(&mut self, va: VirtAddr, data: *mut u8, flags: PageTableFlags) {
+        share_frame(data as u64);
+        self.map_frame(va, data, flags | SHARED_PAGE);
+    }
+    /// Maps a physical frame that this address space does not own (device memory).
+    pub fn map_phys(&mut self, va: VirtAddr, phys: PhysAddr, flags: PageTableFlags) {
+        self.map_raw(va, PhysFrame::containing_address(phys), flags);
+    }
+    fn flags_of(&mut self, va: VirtAddr) -> Option<PageTableFlags> {
+        use x86_64::structures::paging::{mapper::TranslateResult, MapperAllSizes};
+        match self.mapper().translate(va) {
+            TranslateResult::Frame4KiB { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+    pub fn is_mapped(&mut self, va: VirtAddr) -> bool {
+        use x86_64::structures::paging::MapperAllSizes;
+        self.mapper().translate_addr(va).is_some()
+    }
+    /// Unmaps `va`, freeing the page behind it if it is owned. Returns false if nothing was mapped.
+    pub fn unmap(&mut self, va: VirtAddr) -> bool {
+        match self
+            .mapper()
+            .unmap(Page::<Size4KiB>::containing_address(va))
+        {
+            Ok((_, flush)) => {
+                flush.flush();
+                if let Some(f) = self.frames.remove(&(va.as_u64() & !0xfff)) {
+                    release_frame(f);
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+    /// Changes the flags of a mapped page. Shared pages stay copy-on-write instead of becoming writable.
+    pub fn update_flags(&mut self, va: VirtAddr, mut flags: PageTableFlags) -> bool {
+        if let Some(f) = self.frames.get(&(va.as_u64() & !0xfff)) {
+            if is_shared(*f)
+                && flags.contains(PageTableFlags::WRITABLE)
+                && !flags.contains(SHARED_PAGE)
+            {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COW_PAGE);
+            }
+        }
+        match unsafe {
+            self.mapper().update_flags(
+                Page::<Size4KiB>::containing_address(va),
+                PageTableFlags::PRESENT | flags,
+            )
+        } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Maps a zeroed page at `va` if it is inside a region but not mapped yet.
+    /// Returns false if there is nothing to map there.
+    pub fn populate(&mut self, va: VirtAddr) -> bool {
+        let r = match self.region_for(va.as_u64()) {
+            Some(r) => r,
+            None => return false,
+        };
+        if self.is_mapped(va) {
+            return false;
+        }
+        self.map(va, r.flags);
+        true
+    }
+    /// Duplicates this address space, sharing every owned page copy-on-write with the copy.
+    /// Pages mapped with `map_phys` are not carried over.
+    pub fn fork(&mut self) -> AddressSpace {
+        let mut child = AddressSpace::new();
+        let pages: Vec<(u64, u64)> = self.frames.iter().map(|(v, f)| (*v, *f)).collect();
+        for (va, f) in pages {
+            let va = VirtAddr::new(va);
+            let mut flags = self.flags_of(va).expect("owned page is not mapped");
+            if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED_PAGE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COW_PAGE);
+                self.update_flags(va, flags);
+            }
+            share_frame(f);
+            child.map_frame(va, f as *mut u8, flags);
+        }
+        child.regions = self.regions.clone();
+        child.program_break = self.program_break;
+        child
+    }
+    /// Resolves a write to a copy-on-write page, copying it if it is still shared.
+    /// Returns false if `va` is not a copy-on-write page.
+    pub fn handle_cow(&mut self, va: VirtAddr) -> bool {
+        let page = va.as_u64() & !0xfff;
+        let f = match self.frames.get(&page) {
+            Some(f) => *f,
+            None => return false,
+        };
+        let mut flags = match self.flags_of(va) {
+            Some(flags) if flags.contains(COW_PAGE) => flags,
+            _ => return false,
+        };
+        flags.remove(COW_PAGE);
+        flags.insert(PageTableFlags::WRITABLE);
+        if !is_shared(f) {
+            // the other side already copied or exited, the page is ours
+            return self.update_flags(VirtAddr::new(page), flags);
+        }
+        let copy = super::mpage();
+        unsafe {
+            faster_rlibc::fastermemcpy(copy, f as *const u8, 4096);
+        }
+        let (_, flush) = self
+            .mapper()
+            .unmap(Page::<Size4KiB>::containing_address(va))
+            .expect("owned page is not mapped");
+        flush.flush();
+        release_frame(f);
+        self.map_frame(VirtAddr::new(page), copy, flags);
+        true
+    }
+
+    /// Lowest address in `[lo, hi)` with `len` bytes not covered by any region.
+    pub fn find_free(&self, len: u64, lo: u64, hi: u64) -> Option<u64> {
+        let mut cur = lo;
+        for r in self.regions.values() {
+            if r.end() <= cur {
+                continue;
+            }
+            if r.start >= hi {
+                break;
+            }
+            if r.start >= cur && r.start - cur >= len {
+                return Some(cur);
+            }
+            cur = cur.max(r.end());
+        }
+        if hi >= cur && hi - cur >= len {
+            Some(cur)
+        } else {
+            None
+        }
+    }
+    pub fn add_region(&mut self, r: Region) {
+        self.regions.insert(r.start, r);
+    }
+    pub fn region_for(&self, va: u64) -> Option<Region> {
+        self.regions
+            .range(..=va)
+            .next_back()
+            .map(|(_, r)| *r)
+            .filter(|r| va < r.end())
+    }
+    /// Whether every byte of `[start, end)` is inside some region.
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        let mut cur = start;
+        while cur < end {
+            match self.region_for(cur) {
+                Some(r) => cur = r.end(),
+                None => return false,
+            }
+        }
+        true
+    }
+    /// Cuts `[start, end)` out of the regions, splitting the ones that stick out,
+    /// and returns the pieces that were inside.
+    pub fn take_range(&mut self, start: u64, end: u64) -> Vec<Region> {
+        let hits: Vec<Region> = self
+            .regions
+            .values()
+            .filter(|r| r.start < end && start < r.end())
+            .copied()
+            .collect();
+        let mut taken = vec![];
+        for r in hits {
+            self.regions.remove(&r.start);
+            if r.start < start {
+                self.add_region(Region {
+                    start: r.start,
+                    len: start - r.start,
+                    flags: r.flags,
+                });
+            }
+            if r.end() > end {
+                self.add_region(Region {
+                    start: end,
+                    len: r.end() - end,
+                    flags: r.flags,
+                });
+            }
+            let s = r.start.max(start);
+            let e = r.end().min(end);
+            taken.push(Region {
+                start: s,
+                len: e - s,
+                flags: r.flags,
+            });
+        }
+        taken
+    }
+}
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.l4_frame,
+            "dropping the active address space"
+        );
+        for (_, f) in &self.frames {
+            release_frame(*f);
+        }
+        for t in &self.tables {
+            super::fpage(*t as *mut u8);
+        }
+        super::fpage(self.l4 as *mut u8);
+    }
+}
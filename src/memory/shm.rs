@@ -0,0 +1,70 @@
+// shared memory objects that several processes map at once
+use super::aspace::{self, AddressSpace, Region};
+use crate::prelude::*;
+use x86_64::structures::paging::PageTableFlags;
+
+pub struct Shm {
+    /// kernel heap pages; the object holds one reference to each, mappings hold the others
+    frames: Vec<u64>,
+    /// handles to it, counting ones in flight in messages. The object goes away with the last one
+    refs: u64,
+}
+/// Pages of the largest object (64M). Objects are allocated up front, so this is kept sane.
+pub const MAX_PAGES: u64 = 16384;
+/// Pages of all objects together.
+pub const MAX_TOTAL_PAGES: u64 = 65536;
+counter!(SHM_COUNTER);
+counter!(SHM_PAGES);
+ezy_static! { SHM, BTreeMap<u64, Shm>, BTreeMap::new() }
+
+fn destroy(shm: Shm) {
+    SHM_PAGES::subn(shm.frames.len());
+    for f in shm.frames {
+        aspace::release_frame(f);
+    }
+}
+/// Makes a zeroed object of `pages` pages with one reference, returning its id.
+/// ENOMEM past MAX_PAGES, or MAX_TOTAL_PAGES for all objects.
+pub fn create(pages: u64) -> SysResult<u64> {
+    if pages > MAX_PAGES || SHM_PAGES::get() as u64 + pages > MAX_TOTAL_PAGES {
+        return Err(SysError::ENOMEM);
+    }
+    SHM_PAGES::addn(pages as usize);
+    let frames = (0..pages)
+        .map(|_| {
+            let p = super::mpage();
+            unsafe {
+                faster_rlibc::fastermemset(p, 0, 4096);
+            }
+            p as u64
+        })
+        .collect();
+    let id = SHM_COUNTER::inc() as u64;
+    SHM.get().insert(id, Shm { frames, refs: 1 });
+    Ok(id)
+}
+/// Maps the whole object somewhere in the mmap area of `a`, returning the address.
+pub fn map(id: u64, a: &mut AddressSpace, flags: PageTableFlags) -> SysResult<u64> {
+    let shm = SHM.get().get_mut(&id).ok_or(SysError::ENOENT)?;
+    let len = shm.frames.len() as u64 * 4096;
+    let start = a
+        .find_free(len, userland::MMAP_BASE, userland::MMAP_END)
+        .ok_or(SysError::ENOMEM)?;
+    let flags = flags | memory::allocator::SHARED_PAGE;
+    a.add_region(Region { start, len, flags });
+    for (i, f) in shm.frames.iter().enumerate() {
+        a.map_shared(VirtAddr::new(start + i as u64 * 4096), *f as *mut u8, flags);
+    }
+    Ok(start)
+}
+pub fn retain(id: u64) {
+    SHM.get().get_mut(&id).unwrap().refs += 1;
+}
+/// Drops a reference. Existing mappings stay valid until they are unmapped.
+pub fn release(id: u64) {
+    let shm = SHM.get().get_mut(&id).unwrap();
+    shm.refs -= 1;
+    if shm.refs == 0 {
+        destroy(SHM.get().remove(&id).unwrap());
+    }
+}
@@ -3,7 +3,8 @@ use crate::prelude::*;
 use safety_here::Jmpbuf;
 use x86_64::{
     instructions::tables::{lgdt, load_tss},
-    registers::control::Cr3,
+    registers::control::{Cr3, Cr3Flags},
+    registers::model_specific::{FsBase, GsBase},
     structures::{
         gdt::GlobalDescriptorTable, paging::mapper::MapToError, paging::FrameAllocator,
         paging::Mapper, paging::Page, paging::PageTableFlags, paging::PhysFrame, paging::Size4KiB,
@@ -40,39 +41,119 @@ pub fn stack_alloc(stack_size: u64) -> Result<*const u8, MapToError<Size4KiB>> {
     }
     Ok(base as *const u8)
 }
-static TASK_QUEUE_CUR: AtomicUsize = AtomicUsize::new(0);
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum WakeType {
-    WakeConnection,
-    WakeServerReady,
     WakeProcessExited { code: u64 },
     WakeResponded,
+    WakeThreadExited,
+    WakeFutex,
+    WakeTimeout,
+    WakeMessage,
+    WakeServiceGone,
 }
 
+/// What a parked task is waiting for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaitReason {
+    /// a reply to its send
+    Reply,
+    /// room in a full service queue
+    QueueFull,
+    /// a message to accept
+    Message,
+    Poll,
+    /// a service name to be registered
+    Lookup,
+    Futex,
+    Child,
+    Join,
+}
+impl WaitReason {
+    pub fn is_ipc(self) -> bool {
+        match self {
+            WaitReason::Reply
+            | WaitReason::QueueFull
+            | WaitReason::Message
+            | WaitReason::Poll
+            | WaitReason::Lookup => true,
+            _ => false,
+        }
+    }
+}
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Wakeop {
     pub wake_type: WakeType,
     pub waker: u64,
 }
-// privesc would be CURRENT_TASK.get().uid = 0. just sayin' you know.
-#[derive(Copy, Clone, Debug)]
+// privesc would be task().uid = 0. just sayin' you know.
+#[derive(Clone, Debug)]
 pub struct Task {
     pub state: Jmpbuf,
     pub rsp0: VirtAddr,
     pub rsp_ptr: VirtAddr,
     pub pid: u64,
+    /// Unique per task; the first thread of a process has `tid == pid`.
+    pub tid: u64,
     pub box1: Option<&'static [u8]>,
     pub box2: Option<&'static [u8]>,
-    pub program_break: u64,
     pub wakeop: Option<Wakeop>,
-    pub needs_wake: bool,
     pub uid: i32,
     pub currently_responding_to: u64,
+    /// Base of the kernel stack made by `jump_to_task`, 0 if the task runs on a boot stack.
+    pub kstack: u64,
+    pub exit_code: Option<u64>,
+    pub ppid: u64,
+    /// Child pid this task is blocked on in `wait_child`, 0 for any child.
+    pub waiting_for: Option<u64>,
+    /// Thread this task is blocked on in `join_thread`.
+    pub joining: Option<u64>,
+    /// Top of the user stack made by thread_spawn, 0 for the first thread.
+    pub ustack: u64,
+    /// Set right before yielding to keep the task off the run queue until `unpark`.
+    pub parked: bool,
+    /// Tick at which a parked task is woken anyway, 0 for none.
+    pub deadline: u64,
+    /// NICE_MIN to NICE_MAX, lower runs first.
+    pub nice: i8,
+    /// Ticks left of its timeslice.
+    pub slice: u64,
+    /// What it is parked on, while it is.
+    pub wait_reason: Option<WaitReason>,
+    /// Tick it was last parked at.
+    pub parked_at: u64,
+    /// Timer ticks it was running for.
+    pub ticks: u64,
+    /// Times it was switched to.
+    pub switches: u64,
+    pub syscalls: u64,
+    /// Ticks spent parked on IPC.
+    pub ipc_ticks: u64,
+    /// x87/SSE registers while the task is switched out.
+    pub fpu: crate::fpu::FpuState,
+    /// Address of the trap frame of its last entry from user mode, 0 if it never left the kernel.
+    pub frame: u64,
+    /// FS/GS base MSRs (thread pointers for TLS), swapped on every context switch.
+    pub fs_base: u64,
+    pub gs_base: u64,
+    /// None for kernel tasks, which run on the kernel page tables.
+    pub aspace: Option<memory::aspace::AddressSpaceRef>,
+    /// Path and image of the user program, for crash reports.
+    pub exe: Option<(String, &'static [u8])>,
+}
+impl Task {
+    /// Registers of its user code as of its last kernel entry. Good while the task is switched
+    /// out or in a syscall or exception, which is whenever anyone else can look.
+    pub fn user_regs(&self) -> Option<&'static crate::trap::TrapFrame> {
+        if self.frame == 0 {
+            return None;
+        }
+        Some(unsafe { &*(self.frame as *const crate::trap::TrapFrame) })
+    }
 }
 pub mod glblutil {
     use crate::prelude::*;
     pub fn task() -> &'static mut preempt::Task {
-        preempt::CURRENT_TASK.get()
+        preempt::current()
     }
     pub fn pid() -> u64 {
         task().pid
@@ -81,48 +162,505 @@ pub mod glblutil {
         preempt::yield_task();
     }
 }
-ezy_static! { TASK_QUEUE, Vec<Task>, vec![Task { state: Jmpbuf::new(), rsp0: crate::interrupts::get_rsp0(), rsp_ptr: crate::userland::alloc_rsp_ptr("syscall-stack:/bin/init".to_string()), pid: 1, box1: None, box2: None, program_break: 0, wakeop: None, needs_wake: false, uid: -1, currently_responding_to: 0 }] }
-ezy_static! { CURRENT_TASK, Task, Task { state: Jmpbuf::new(), rsp0: crate::interrupts::get_rsp0(), rsp_ptr: crate::userland::alloc_rsp_ptr("fake stack".to_string()), pid: 1, box1: None, box2: None, program_break: 0, wakeop: None, needs_wake: false, uid: -1, currently_responding_to: 0 } }
-extern "C" fn get_next(buf: &mut Jmpbuf) {
-    let tq = TASK_QUEUE.get();
-    let mut ct = CURRENT_TASK.clone();
-    ct.state = buf.clone();
-    tq[TASK_QUEUE_CUR.fetch_add(1, Ordering::Relaxed)] = ct;
-    TASK_QUEUE_CUR.store(
-        TASK_QUEUE_CUR.load(Ordering::Relaxed) % tq.len(),
-        Ordering::Relaxed,
+/// Nice values go from NICE_MIN (most CPU) to NICE_MAX; tasks start at 0.
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+const LEVELS: usize = (NICE_MAX - NICE_MIN + 1) as usize;
+fn level(nice: i8) -> usize {
+    (nice - NICE_MIN) as usize
+}
+/// Ticks a task runs before it is preempted: 11 at nice -20, 6 at 0, 1 at 19.
+fn timeslice(nice: i8) -> u64 {
+    ((20 - nice as i64) / 4 + 1) as u64
+}
+/// A FIFO of tids per priority level, and a bitmap of the levels that have any.
+pub struct Levels {
+    queues: Vec<VecDeque<u64>>,
+    bitmap: u64,
+}
+impl Levels {
+    fn new() -> Levels {
+        Levels {
+            queues: (0..LEVELS).map(|_| VecDeque::new()).collect(),
+            bitmap: 0,
+        }
+    }
+    fn push(&mut self, level: usize, tid: u64) {
+        self.queues[level].push_back(tid);
+        self.bitmap |= 1 << level;
+    }
+    fn pop(&mut self) -> Option<u64> {
+        if self.bitmap == 0 {
+            return None;
+        }
+        let level = self.bitmap.trailing_zeros() as usize;
+        let tid = self.queues[level].pop_front();
+        if self.queues[level].is_empty() {
+            self.bitmap &= !(1 << level);
+        }
+        tid
+    }
+}
+/// Runnable tasks. The next one comes from the best non-empty level, found with one bitmap scan.
+/// A task that used up its timeslice waits in `expired` until everything in `active` had its
+/// turn, so nice tasks still get to run.
+pub struct RunQueue {
+    active: Levels,
+    expired: Levels,
+}
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            active: Levels::new(),
+            expired: Levels::new(),
+        }
+    }
+    fn push(&mut self, t: &mut Task) {
+        if t.slice == 0 {
+            t.slice = timeslice(t.nice);
+            self.expired.push(level(t.nice), t.tid);
+        } else {
+            self.active.push(level(t.nice), t.tid);
+        }
+    }
+    fn pop(&mut self) -> Option<u64> {
+        if self.active.bitmap == 0 {
+            core::mem::swap(&mut self.active, &mut self.expired);
+        }
+        self.active.pop()
+    }
+    /// Whether a task with a better level than `level` is waiting.
+    fn preempts(&self, level: usize) -> bool {
+        self.active.bitmap & ((1 << level) - 1) != 0
+    }
+}
+fn boot_task() -> BTreeMap<u64, Box<Task>> {
+    let mut tasks = BTreeMap::new();
+    tasks.insert(
+        0,
+        box Task {
+            state: Jmpbuf::new(),
+            rsp0: crate::interrupts::get_rsp0(),
+            rsp_ptr: crate::userland::alloc_rsp_ptr("syscall-stack:/bin/init".to_string()),
+            pid: 1,
+            tid: 0,
+            box1: None,
+            box2: None,
+            wakeop: None,
+            uid: -1,
+            currently_responding_to: 0,
+            kstack: 0,
+            exit_code: None,
+            ppid: 0,
+            waiting_for: None,
+            joining: None,
+            ustack: 0,
+            parked: false,
+            deadline: 0,
+            nice: 0,
+            slice: timeslice(0),
+            wait_reason: None,
+            parked_at: 0,
+            ticks: 0,
+            switches: 0,
+            syscalls: 0,
+            ipc_ticks: 0,
+            fpu: crate::fpu::FpuState::new(),
+            frame: 0,
+            fs_base: 0,
+            gs_base: 0,
+            aspace: None,
+            exe: None,
+        },
     );
-    let mut q = tq[TASK_QUEUE_CUR.load(Ordering::Relaxed) % tq.len()];
-    *CURRENT_TASK.get() = q;
+    tasks
+}
+// tid => every task that is not reaped yet: running, runnable or parked.
+// boxed, so references from `task()` stay put while the map changes
+ezy_static! { TASKS, BTreeMap<u64, Box<Task>>, boot_task() }
+ezy_static! { RUN_QUEUE, RunQueue, RunQueue::new() }
+static CURRENT: AtomicPtr<Task> = AtomicPtr::new(core::ptr::null_mut());
+// runs hlt when nothing else can run. never on the run queue
+static IDLE_TID: AtomicU64 = AtomicU64::new(u64::MAX);
+ezy_static! { REAP_QUEUE, Vec<Box<Task>>, vec![] }
+// (tick, tid) of parked tasks that get woken anyway, soonest first
+ezy_static! { DEADLINES, BTreeSet<(u64, u64)>, BTreeSet::new() }
+ezy_static! { EXIT_CODES, BTreeMap<u64, (u64, u64)>, BTreeMap::new() }
+// tid => (pid, exit code) of threads nobody has joined yet
+ezy_static! { THREAD_EXITS, BTreeMap<u64, (u64, u64)>, BTreeMap::new() }
+ezy_static! { KERNEL_CR3, (PhysFrame, Cr3Flags), Cr3::read() }
 
-    while CURRENT_TASK.get().needs_wake {
-        tq[TASK_QUEUE_CUR.fetch_add(1, Ordering::Relaxed)] = q;
-        TASK_QUEUE_CUR.store(
-            TASK_QUEUE_CUR.load(Ordering::Relaxed) % tq.len(),
-            Ordering::Relaxed,
-        );
-        q = tq[TASK_QUEUE_CUR.load(Ordering::Relaxed) % tq.len()];
-        *CURRENT_TASK.get() = q;
+// the dead task's stacks are still in use while it is in get_next, so they get freed on the next switch.
+// its address space goes away with the last task holding it
+fn reap() {
+    for t in REAP_QUEUE.get().drain(..) {
+        if let Some(s) = t.box1 {
+            unsafe {
+                Box::from_raw(s as *const [u8] as *mut [u8]);
+            }
+        }
+        if let Some(s) = t.box2 {
+            unsafe {
+                Box::from_raw(s as *const [u8] as *mut [u8]);
+            }
+        }
+        if t.kstack != 0 {
+            crate::interrupts::free_rsp0(t.rsp0);
+            crate::userland::free_rsp_ptr(t.rsp_ptr);
+            unsafe {
+                Box::from_raw(core::slice::from_raw_parts_mut(
+                    t.kstack as *mut u64,
+                    STACK_SIZE_IN_QWORDS,
+                ));
+            }
+        }
+    }
+}
+fn wake_expired() {
+    let now = crate::interrupts::ticks();
+    while let Some(&(tick, tid)) = DEADLINES.get().iter().next() {
+        if tick > now {
+            break;
+        }
+        DEADLINES.get().remove(&(tick, tid));
+        unpark(tid, WakeType::WakeTimeout, 0);
+    }
+}
+/// The running task. Before the first switch that is the boot task.
+pub fn current() -> &'static mut Task {
+    let mut p = CURRENT.load(Ordering::Relaxed);
+    if p.is_null() {
+        p = &mut **TASKS.get().get_mut(&0).unwrap();
+        CURRENT.store(p, Ordering::Relaxed);
+    }
+    unsafe { &mut *p }
+}
+/// Gives the running task a new thread id, keeping `TASKS` keyed by it.
+pub fn set_tid(tid: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let old = current().tid;
+        let t = TASKS.get().remove(&old).unwrap();
+        // boxed, so `current()` still points at it
+        TASKS.get().insert(tid, t);
+        current().tid = tid;
+    });
+}
+fn idle(_: u64) {
+    loop {
+        x86_64::instructions::hlt();
     }
+}
+/// Makes the idle task. Has to happen before the first task switch.
+pub fn init() {
+    current();
+    let tid = crate::userland::mkpid();
+    let t = make_task(idle, 0, "idle".to_string(), 1, tid, 0, NICE_MAX);
+    TASKS.get().insert(tid, box t);
+    IDLE_TID.store(tid, Ordering::Relaxed);
+}
+extern "C" fn get_next(buf: &mut Jmpbuf) {
+    reap();
+    let idle = IDLE_TID.load(Ordering::Relaxed);
+    let ct = task();
+    ct.state = buf.clone();
+    ct.fs_base = FsBase::read().as_u64();
+    ct.gs_base = GsBase::read().as_u64();
+    ct.fpu.save();
+    if ct.exit_code.is_some() {
+        REAP_QUEUE.get().push(TASKS.get().remove(&ct.tid).unwrap());
+    } else if !ct.parked && ct.tid != idle {
+        RUN_QUEUE.get().push(ct);
+    }
+    // only now: a task that just parked with a deadline that already passed is not queued above,
+    // so this queues it exactly once
+    wake_expired();
+    let q = loop {
+        let tid = RUN_QUEUE.get().pop().unwrap_or(idle);
+        let t = TASKS.get().get_mut(&tid).expect("no idle task");
+        if t.exit_code.is_some() {
+            // a thread whose process exited while it was not running
+            REAP_QUEUE.get().push(TASKS.get().remove(&tid).unwrap());
+            continue;
+        }
+        break t;
+    };
+    CURRENT.store(&mut **q, Ordering::Relaxed);
+    q.switches += 1;
 
     crate::interrupts::set_rsp0(q.rsp0);
+    FsBase::write(VirtAddr::new(q.fs_base));
+    GsBase::write(VirtAddr::new(q.gs_base));
     crate::userland::set_rsp_ptr(q.rsp_ptr);
-    *buf = q.state;
+    match &q.aspace {
+        Some(a) => a.get().activate(),
+        None => unsafe {
+            Cr3::write(KERNEL_CR3.0, KERNEL_CR3.1);
+        },
+    }
+    q.fpu.restore();
+    *buf = q.state.clone();
+}
+/// Called on every timer tick. Wakes tasks whose deadline passed; the running task is switched
+/// out once its timeslice is used up, or right away if a task with a better priority is waiting.
+pub fn tick() {
+    wake_expired();
+    let t = task();
+    t.ticks += 1;
+    t.slice = t.slice.saturating_sub(1);
+    if t.slice == 0
+        || t.tid == IDLE_TID.load(Ordering::Relaxed)
+        || RUN_QUEUE.get().preempts(level(t.nice))
+    {
+        yield_task();
+    }
+}
+/// Changes the nice value of `t`. A queued task moves to its new level the next time it is queued.
+pub fn set_nice(t: &mut Task, nice: i8) {
+    t.nice = nice;
+    t.slice = t.slice.min(timeslice(nice));
 }
 pub fn yield_task() -> () {
     x86_64::instructions::interrupts::without_interrupts(|| {
-        let c = Cr3::read();
         // set ourselves up as a task.
         let mut bufl = Jmpbuf::new();
 
         unsafe {
             safety_here::changecontext(get_next, &mut bufl);
         }
-        unsafe {
-            Cr3::write(c.0, c.1);
-        }
     });
 }
+/// Whether `t` is another thread of the current process. Kernel tasks all share pid 1 but are not threads.
+fn is_sibling(t: &Task) -> bool {
+    t.pid == task().pid && t.tid != task().tid && t.aspace.is_some() && task().aspace.is_some()
+}
+/// Exits the whole process, taking its other threads with it.
+pub fn exit_task(code: u64) -> ! {
+    x86_64::instructions::interrupts::disable();
+    dprintln!("[preempt] pid {} exited with code {}", task().pid, code);
+    let me = task().pid;
+    let ppid = task().ppid;
+    task().exit_code = Some(code);
+    // orphans and kernel threads (ppid 0) have nobody to wait for them
+    if ppid != 0 {
+        EXIT_CODES.get().insert(me, (ppid, code));
+    }
+    // nobody is left to wait for our own children or join our threads
+    EXIT_CODES.get().retain(|_, v| v.0 != me);
+    THREAD_EXITS.get().retain(|_, v| v.0 != me);
+    let mut dead = vec![task().tid];
+    let mut parent = None;
+    for t in tasks() {
+        if is_sibling(t) {
+            t.exit_code = Some(code);
+            dead.push(t.tid);
+        }
+        if t.ppid == me {
+            t.ppid = 0;
+        }
+        if parent.is_none()
+            && t.pid == ppid
+            && t.parked
+            && (t.waiting_for == Some(me) || t.waiting_for == Some(0))
+        {
+            t.waiting_for = None;
+            parent = Some(t.tid);
+        }
+    }
+    if let Some(tid) = parent {
+        unpark(tid, WakeType::WakeProcessExited { code }, me);
+    }
+    crate::ipc::exit(me, &dead);
+    crate::handle::exit(me, &dead);
+    // parked threads never get to run again, reap them right away
+    let killed: Vec<u64> = tasks()
+        .filter(|t| t.parked && t.exit_code.is_some())
+        .map(|t| t.tid)
+        .collect();
+    for tid in killed {
+        let t = TASKS.get().remove(&tid).unwrap();
+        DEADLINES.get().remove(&(t.deadline, tid));
+        REAP_QUEUE.get().push(t);
+    }
+    loop {
+        yield_task();
+    }
+}
+/// Exits only the current thread; the last thread of a process exits the process.
+pub fn exit_thread(code: u64) -> ! {
+    x86_64::instructions::interrupts::disable();
+    if !tasks().any(|t| is_sibling(t) && t.exit_code.is_none())
+    {
+        exit_task(code);
+    }
+    let me = task().tid;
+    task().exit_code = Some(code);
+    THREAD_EXITS.get().insert(me, (task().pid, code));
+    crate::ipc::exit_thread(me);
+    crate::handle::exit_thread(me);
+    let joiners: Vec<u64> = tasks()
+        .filter(|t| t.parked && t.joining == Some(me))
+        .map(|t| t.tid)
+        .collect();
+    for tid in joiners {
+        queued_task(tid).unwrap().joining = None;
+        unpark(tid, WakeType::WakeThreadExited, me);
+    }
+    loop {
+        yield_task();
+    }
+}
+/// Blocks until thread `tid` of the current process exits, returning its exit code.
+/// Returns `None` if there is no such thread.
+pub fn join_thread(tid: u64) -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| loop {
+        let me = task().pid;
+        if let Some((_, code)) = THREAD_EXITS.get().get(&tid).filter(|v| v.0 == me) {
+            let code = *code;
+            THREAD_EXITS.get().remove(&tid);
+            return Some(code);
+        }
+        if tid == task().tid
+            || !tasks().any(|t| t.tid == tid && is_sibling(t))
+        {
+            return None;
+        }
+        task().joining = Some(tid);
+        park(WaitReason::Join, None);
+    })
+}
+/// The queued or parked (not running) task with thread id `tid`.
+pub fn queued_task(tid: u64) -> Option<&'static mut Task> {
+    tasks().find(|t| t.tid == tid)
+}
+/// Every task that is not running right now, runnable or parked. The idle task does not count.
+pub fn tasks() -> impl Iterator<Item = &'static mut Task> {
+    let me = task().tid;
+    let idle = IDLE_TID.load(Ordering::Relaxed);
+    TASKS
+        .get()
+        .values_mut()
+        .map(|t| &mut **t)
+        .filter(move |t| t.tid != me && t.tid != idle)
+}
+/// Takes the current task off the run queue until someone calls `unpark` on it or `timeout`
+/// ticks pass, and returns how it was woken. Call with interrupts disabled, after putting the
+/// task on whatever wait queue will wake it.
+pub fn park(reason: WaitReason, timeout: Option<u64>) -> WakeType {
+    if let Some(t) = timeout {
+        task().deadline = crate::interrupts::ticks() + t;
+        DEADLINES.get().insert((task().deadline, task().tid));
+    }
+    task().wakeop = None;
+    task().wait_reason = Some(reason);
+    task().parked_at = crate::interrupts::ticks();
+    task().parked = true;
+    yield_task();
+    task().wakeop.unwrap().wake_type
+}
+/// Puts a parked task back on the run queue. Returns false if `tid` is not parked.
+pub fn unpark(tid: u64, wake_type: WakeType, waker: u64) -> bool {
+    let t = match TASKS.get().get_mut(&tid) {
+        Some(t) if t.parked => t,
+        _ => return false,
+    };
+    if t.deadline != 0 {
+        DEADLINES.get().remove(&(t.deadline, tid));
+        t.deadline = 0;
+    }
+    if t.wait_reason.take().map_or(false, |r| r.is_ipc()) {
+        t.ipc_ticks += crate::interrupts::ticks() - t.parked_at;
+    }
+    t.parked = false;
+    t.wakeop = Some(Wakeop { wake_type, waker });
+    RUN_QUEUE.get().push(t);
+    true
+}
+/// Sets the FS base of the current task, for TLS.
+pub fn set_fs_base(v: VirtAddr) {
+    task().fs_base = v.as_u64();
+    FsBase::write(v);
+}
+pub fn set_gs_base(v: VirtAddr) {
+    task().gs_base = v.as_u64();
+    GsBase::write(v);
+}
+/// Tasks blocked on the same thing, woken in the order they came.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    /// with what each one parks for, so a task that moved on to wait for something else is skipped
+    tids: VecDeque<(u64, WaitReason)>,
+}
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            tids: VecDeque::new(),
+        }
+    }
+    /// Queues the current task; it still has to `park` itself with `reason`.
+    pub fn push_current(&mut self, reason: WaitReason) {
+        self.tids.push_back((task().tid, reason));
+    }
+    pub fn remove(&mut self, tid: u64) {
+        self.tids.retain(|t| t.0 != tid);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tids.is_empty()
+    }
+    /// Wakes up to `n` tasks with `wake_type`, returning how many were woken.
+    pub fn wake(&mut self, n: u64, wake_type: WakeType) -> u64 {
+        let mut woken = 0;
+        while woken < n {
+            match self.tids.pop_front() {
+                Some((tid, reason)) => {
+                    if waiting_for(tid, reason) && unpark(tid, wake_type, task().tid) {
+                        woken += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        woken
+    }
+}
+/// Whether task `tid` is parked on `reason` right now.
+pub fn waiting_for(tid: u64, reason: WaitReason) -> bool {
+    TASKS
+        .get()
+        .get(&tid)
+        .map_or(false, |t| t.parked && t.wait_reason == Some(reason))
+}
+/// Blocks until a child (`pid`, or any child if `pid` is 0) exits, returning its pid and exit code.
+/// Returns `None` if there is no such child.
+pub fn wait_child(pid: u64) -> Option<(u64, u64)> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let me = task().pid;
+        let zombie = EXIT_CODES
+            .get()
+            .iter()
+            .find(|(p, v)| v.0 == me && (pid == 0 || **p == pid))
+            .map(|(p, v)| (*p, v.1));
+        if let Some((p, code)) = zombie {
+            EXIT_CODES.get().remove(&p);
+            return Some((p, code));
+        }
+        if !tasks().any(|t| t.ppid == me && (pid == 0 || t.pid == pid))
+        {
+            return None;
+        }
+        task().waiting_for = Some(pid);
+        park(WaitReason::Child, None);
+        match task().wakeop {
+            Some(Wakeop {
+                wake_type: WakeType::WakeProcessExited { code },
+                waker,
+            }) => {
+                EXIT_CODES.get().remove(&waker);
+                Some((waker, code))
+            }
+            _ => unreachable!(),
+        }
+    })
+}
 // we allow this here; this is a setup call for a new task
 #[allow(improper_ctypes_definitions)]
 extern "C" fn setup_call(
@@ -137,22 +675,65 @@ extern "C" fn setup_call(
 ) -> ! {
     x86_64::instructions::interrupts::enable();
     fcn(arg);
-    loop {
-        yield_task();
-    }
+    exit_task(0);
 }
 
-pub fn task_alloc<T: FnOnce<()>>(f: T, stknm: String) {
+/// Queues a kernel task with pid and tid `pid`, running on the kernel page tables.
+/// See `kthread::spawn` for one that can be joined.
+pub fn task_alloc<T: FnOnce<()>>(f: T, stknm: String, pid: u64) {
+    spawn(f, stknm, pid, pid, 0, 0);
+}
+/// Like `task_alloc`, but the task is queued with its pid and parent already set,
+/// so it can be waited for before it first runs.
+/// Like exec and fork on unix, the new process keeps the caller's nice value.
+pub fn process_alloc<T: FnOnce<()>>(f: T, stknm: String, pid: u64, ppid: u64) {
+    spawn(f, stknm, pid, pid, ppid, task().nice);
+}
+/// Queues another thread of the current process with thread id `tid`, sharing its address space.
+pub fn thread_alloc<T: FnOnce<()>>(f: T, stknm: String, tid: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        spawn(f, stknm, task().pid, tid, task().ppid, task().nice);
+        let t = queued_task(tid).unwrap();
+        t.aspace = task().aspace.clone();
+        t.exe = task().exe.clone();
+        t.uid = task().uid;
+    });
+}
+fn spawn<T: FnOnce<()>>(f: T, stknm: String, pid: u64, tid: u64, ppid: u64, nice: i8) {
     fn run_task_ll<T: FnOnce<()>>(arg: u64) {
         let b = unsafe { Box::from_raw(arg as *mut T) };
         b();
     }
     let b = Box::new(f);
     let ptr = Box::leak(b) as *const T;
-    jump_to_task(run_task_ll::<T>, ptr as u64, stknm);
+    jump_to_task(run_task_ll::<T>, ptr as u64, stknm, pid, tid, ppid, nice);
+}
+const STACK_SIZE_IN_QWORDS: usize = 1024;
+fn jump_to_task(
+    newfcn: fn(arg: u64) -> (),
+    arg: u64,
+    stknm: String,
+    pid: u64,
+    tid: u64,
+    ppid: u64,
+    nice: i8,
+) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        TASKS
+            .get()
+            .insert(tid, box make_task(newfcn, arg, stknm, pid, tid, ppid, nice));
+        RUN_QUEUE.get().push(TASKS.get().get_mut(&tid).unwrap());
+    });
 }
-fn jump_to_task(newfcn: fn(arg: u64) -> (), arg: u64, stknm: String) {
-    const STACK_SIZE_IN_QWORDS: usize = 1024;
+fn make_task(
+    newfcn: fn(arg: u64) -> (),
+    arg: u64,
+    stknm: String,
+    pid: u64,
+    tid: u64,
+    ppid: u64,
+    nice: i8,
+) -> Task {
     let end_of_stack = STACK_SIZE_IN_QWORDS - 2;
     let mut stack: Box<[u64]> = box [0; STACK_SIZE_IN_QWORDS];
     let index: usize = end_of_stack - 1; // Represents the callee saved registers
@@ -172,22 +753,89 @@ fn jump_to_task(newfcn: fn(arg: u64) -> (), arg: u64, stknm: String) {
     b.rsp = stack_ptr_start as u64;
     b.rip = setup_call as *const u8 as u64;
     b.rsi = newfcn as *const u8 as u64;
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        TASK_QUEUE.get().push(Task {
-            state: b,
-            rsp0: crate::interrupts::alloc_rsp0(),
-            rsp_ptr: crate::userland::alloc_rsp_ptr(stknm),
-            pid: 1,
-            box1: None,
-            box2: None,
-            program_break: 0,
-            wakeop: None,
-            needs_wake: false,
-            uid: -1,
-            currently_responding_to: 0,
-        });
-    });
+    Task {
+        state: b,
+        rsp0: crate::interrupts::alloc_rsp0(),
+        rsp_ptr: crate::userland::alloc_rsp_ptr(stknm),
+        pid,
+        tid,
+        box1: None,
+        box2: None,
+        wakeop: None,
+        uid: -1,
+        currently_responding_to: 0,
+        kstack: stack_ptr as *mut u64 as u64,
+        exit_code: None,
+        ppid,
+        waiting_for: None,
+        joining: None,
+        ustack: 0,
+        parked: false,
+        deadline: 0,
+        nice,
+        slice: timeslice(nice),
+        wait_reason: None,
+        parked_at: 0,
+        ticks: 0,
+        switches: 0,
+        syscalls: 0,
+        ipc_ticks: 0,
+        fpu: crate::fpu::FpuState::new(),
+        frame: 0,
+        fs_base: 0,
+        gs_base: 0,
+        aspace: None,
+        exe: None,
+    }
 }
 
 #[cfg(test)]
 static VAL: AtomicU64 = AtomicU64::new(3);
+
+#[test_case]
+fn timeslices() {
+    crate::testing::test_header("Timeslices by nice value");
+    assert_eq!(timeslice(NICE_MIN), 11);
+    assert_eq!(timeslice(0), 6);
+    assert_eq!(timeslice(NICE_MAX), 1);
+    crate::testing::test_ok();
+}
+
+#[test_case]
+fn levels_pop_best_first() {
+    crate::testing::test_header("Levels pop the best level first, FIFO within one");
+    let mut l = Levels::new();
+    l.push(level(5), 1);
+    l.push(level(-3), 2);
+    l.push(level(5), 3);
+    l.push(level(NICE_MAX), 4);
+    assert_eq!(l.pop(), Some(2));
+    assert_eq!(l.pop(), Some(1));
+    assert_eq!(l.pop(), Some(3));
+    assert_eq!(l.pop(), Some(4));
+    assert_eq!(l.bitmap, 0);
+    assert_eq!(l.pop(), None);
+    crate::testing::test_ok();
+}
+
+#[test_case]
+fn run_queue_expires_used_up_slices() {
+    crate::testing::test_header("Run queue keeps expired tasks until the active ones ran");
+    let mut rq = RunQueue::new();
+    let mut t = make_task(idle, 0, "test:run-queue".to_string(), 1, 100, 0, 0);
+    t.slice = 0;
+    rq.push(&mut t);
+    assert_eq!(t.slice, timeslice(0));
+    t.tid = 101;
+    t.nice = 10;
+    rq.push(&mut t);
+    // 101 is worse but still has its slice, 100 waits for the next round
+    assert!(rq.preempts(level(NICE_MAX)));
+    assert!(!rq.preempts(level(10)));
+    assert_eq!(rq.pop(), Some(101));
+    assert_eq!(rq.pop(), Some(100));
+    assert_eq!(rq.pop(), None);
+    REAP_QUEUE.get().push(box t);
+    reap();
+    crate::testing::test_ok();
+}
@@ -5,6 +5,7 @@ pub use crate::{
     testing,
 };
 pub use alloc::format;
+pub use errno::{SysError, SysResult};
 pub use alloc::{boxed::Box, collections::*, collections::*, string::*, vec, vec::Vec};
 use core::ops::{Deref, DerefMut};
 pub use core::sync::atomic::*;
@@ -1 +1,93 @@
+// ps and top: what every task is doing and how much cpu it used
+use crate::prelude::*;
+use preempt::Task;
 
+// tick of the last `top` and the ticks every task had used by then
+ezy_static! { LAST_TOP, (u64, BTreeMap<u64, u64>), (0, BTreeMap::new()) }
+
+fn state(t: &Task) -> &'static str {
+    if t.tid == task().tid {
+        "running"
+    } else if t.exit_code.is_some() {
+        "exiting"
+    } else if t.parked {
+        "blocked"
+    } else {
+        "runnable"
+    }
+}
+/// The name the task's syscall stack was allocated with, which says what it was spawned as.
+fn name(t: &Task) -> String {
+    stack_canaries::CANARIES
+        .get()
+        .iter()
+        // canaries are kept by stack start, rsp_ptr is the stack end
+        .find(|c| c.0 + c.2 == t.rsp_ptr)
+        .map_or("?".to_string(), |c| c.1.clone())
+}
+fn header(first: &str) {
+    println!(
+        "{:>5} {:>5} {:>5} {:>5} {:>3} {:<8} {:<9} {:>7} {:>7} {:>7} {:>7} NAME",
+        first, "TID", "PID", "PPID", "NI", "STATE", "WAIT", "TICKS", "SWITCH", "SYSCALL", "IPC"
+    );
+}
+fn row(first: String, t: &Task) {
+    let wait = t
+        .wait_reason
+        .filter(|_| t.parked)
+        .map_or("-".to_string(), |w| format!("{:?}", w));
+    println!(
+        "{:>5} {:>5} {:>5} {:>5} {:>3} {:<8} {:<9} {:>7} {:>7} {:>7} {:>7} {}",
+        first,
+        t.tid,
+        t.pid,
+        t.ppid,
+        t.nice,
+        state(t),
+        wait,
+        t.ticks,
+        t.switches,
+        t.syscalls,
+        t.ipc_ticks,
+        name(t)
+    );
+}
+/// Lists every task. TICKS is cpu time and IPC time blocked in send, accept, poll and lookup, both in timer ticks.
+pub fn ps() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        header("");
+        for t in preempt::TASKS.get().values() {
+            row(String::new(), t);
+        }
+    });
+}
+/// Like `ps`, busiest first, with the share of cpu each task got since the last `top`.
+pub fn top() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = interrupts::ticks();
+        let (since, last) = LAST_TOP.get();
+        let span = (now - *since).max(1);
+        let mut rows: Vec<(u64, &Task)> = preempt::TASKS
+            .get()
+            .values()
+            .map(|t| (t.ticks.saturating_sub(*last.get(&t.tid).unwrap_or(&0)), &**t))
+            .collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0));
+        let blocked = rows.iter().filter(|(_, t)| t.parked).count();
+        println!(
+            "up {}s, {} tasks, {} blocked, over the last {}ms:",
+            interrupts::ticks_to_ms(now) / 1000,
+            rows.len(),
+            blocked,
+            interrupts::ticks_to_ms(span)
+        );
+        header("%CPU");
+        for (used, t) in &rows {
+            row(format!("{}", used * 100 / span), t);
+        }
+        *LAST_TOP.get() = (
+            now,
+            rows.iter().map(|(_, t)| (t.tid, t.ticks)).collect(),
+        );
+    });
+}
@@ -105,6 +105,8 @@ pub async fn shell() {
     ecmd!(user, crate::userland::loaduser());
     ecmd!(gptt, drive::gpt::test0());
     ecmd!(pci, crate::pci::testing());
+    ecmd!(ps, crate::proc::ps());
+    ecmd!(top, crate::proc::top());
 
     loop {
         print!("\x1b[44m\x1b[30m ~ \x1b[0m\x1b[34m\u{e0b0}\x1b[0m ");
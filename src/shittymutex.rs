@@ -1,5 +1,6 @@
 use core::{
     cell::UnsafeCell,
+    fmt,
     ops::{Deref, DerefMut},
 };
 
@@ -32,6 +33,13 @@ impl<T> DerefMut for Mutex<T> {
         unsafe { &mut *self.data.get() }
     }
 }
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("data", &unsafe { &*self.data.get() })
+            .finish()
+    }
+}
 impl<T> Mutex<T> {
     pub const fn new(data: T) -> Mutex<T> {
         Mutex {
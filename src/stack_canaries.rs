@@ -13,6 +13,9 @@ pub fn add_canary(p: VirtAddr, s: String, size: u64) {
     }
     CANARIES.get().push((p, s.clone(), size));
 }
+pub fn remove_canary(p: VirtAddr) {
+    CANARIES.get().retain(|c| c.0 != p);
+}
 
 extern "C" {
     #[link_name = "llvm.returnaddress"]
@@ -38,7 +41,7 @@ impl CleaningAlloc {
             if ReentrancyGuard::inc() != 1 {
                 println!(
                     "=={}== ERROR: AddressCleaner memory-alloc-reentrancy at pc {:p}",
-                    preempt::CURRENT_TASK.pid,
+                    task().pid,
                     return_address(0)
                 );
                 println!(" => new layout is {:?}", layout);
@@ -46,7 +49,7 @@ impl CleaningAlloc {
                     " => old layout is {:?}",
                     _OLD_TMP_ENTER_LAYOUT.get().unwrap()
                 );
-                println!("=={}== ABORTING", preempt::CURRENT_TASK.pid);
+                println!("=={}== ABORTING", task().pid);
                 panic!("[AddressCleaner abort]");
             }
             *_OLD_TMP_ENTER_LAYOUT.get() = Some(layout.clone());
@@ -93,7 +96,7 @@ impl CleaningAlloc {
             if ReentrancyGuard::inc() != 1 {
                 println!(
                     "=={}== ERROR: AddressCleaner memory-alloc-reentrancy at pc {:p}",
-                    preempt::CURRENT_TASK.pid,
+                    task().pid,
                     return_address(0)
                 );
                 println!(" => new layout is {:?}", layout);
@@ -101,7 +104,7 @@ impl CleaningAlloc {
                     " => old layout is {:?}",
                     _OLD_TMP_ENTER_LAYOUT.get().unwrap()
                 );
-                println!("=={}== ABORTING", preempt::CURRENT_TASK.pid);
+                println!("=={}== ABORTING", task().pid);
                 panic!("[AddressCleaner abort]");
             }
             *_OLD_TMP_ENTER_LAYOUT.get() = Some(layout.clone());
@@ -125,7 +128,7 @@ impl CleaningAlloc {
                 {
                     println!(
                         "=={}== ERROR: AddressCleaner shadow-space-write (checked at pc {:p})",
-                        preempt::CURRENT_TASK.pid,
+                        task().pid,
                         return_address(0)
                     );
                     println!(" => memory layout is {:?}", layout);
@@ -191,14 +194,14 @@ pub fn ac_check() {
     if ReentrancyGuard::inc() != 1 {
         println!(
             "=={}== ERROR: AddressCleaner panic-in-alloc at pc {:p}",
-            preempt::CURRENT_TASK.pid,
+            task().pid,
             unsafe { return_address(0) }
         );
         println!(
             " => layout to be alloced is {:?}",
             _OLD_TMP_ENTER_LAYOUT.get().unwrap()
         );
-        println!("=={}== ABORTING", preempt::CURRENT_TASK.pid);
+        println!("=={}== ABORTING", task().pid);
         return;
     }
     // AddrLo
@@ -222,7 +225,7 @@ pub fn ac_check() {
                 {
                     println!(
                         "=={}== ERROR: AddressCleaner shadow-space-write (checked at pc {:p})",
-                        preempt::CURRENT_TASK.pid,
+                        task().pid,
                         unsafe { return_address(0) }
                     );
                     println!(" => memory layout is {:?}", layout);
@@ -271,13 +274,13 @@ pub fn stk_chk() {
         if stka != 0xdeadbeef {
             println!(
                 "=={}== ERROR: AddressCleaner stack-overflow on address {:p} at pc {:p} stack {}",
-                preempt::CURRENT_TASK.pid,
+                task().pid,
                 q,
                 unsafe { return_address(0) },
                 c.1
             );
             println!("SMASHED of size 8 at {:p} (now is {:#x?})", q, stka);
-            println!("=={}== ABORTING", preempt::CURRENT_TASK.pid);
+            println!("=={}== ABORTING", task().pid);
         }
     }
 }
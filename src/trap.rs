@@ -0,0 +1,206 @@
+// kernel entry and exit: every interrupt, exception and syscall saves the interrupted code's
+// registers in a TrapFrame on the kernel stack and goes back through it with iretq
+use crate::prelude::*;
+
+/// Vector number in the frames of syscalls, which do not come through the IDT.
+pub const SYSCALL_VECTOR: u64 = 0x80;
+pub const USER_CS: u64 = 0x23;
+pub const USER_SS: u64 = 0x1b;
+
+/// Lowest address first: what `trap_common` pushes, the vector and error code, then what the CPU pushes.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub es: u64,
+    pub ds: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// error code of the exception, 0 if it has none
+    pub error: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+impl TrapFrame {
+    /// A frame that enters user mode at `rip` with stack `rsp`, interrupts on and everything else 0.
+    pub fn user(rip: u64, rsp: u64) -> TrapFrame {
+        TrapFrame {
+            es: USER_SS,
+            ds: USER_SS,
+            rip,
+            cs: USER_CS,
+            rflags: 0x200,
+            rsp,
+            ss: USER_SS,
+            ..TrapFrame::default()
+        }
+    }
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+    /// The interrupted code's x87/SSE state, saved by `trap_common` right below the frame.
+    /// Only meaningful on the frame `trap_common` made, never on a copy of it.
+    pub fn fpu(&self) -> &'static mut fpu::FpuState {
+        let at = (self as *const TrapFrame as u64 - 512) & !15;
+        unsafe { &mut *(at as *mut fpu::FpuState) }
+    }
+    /// Prints the registers three to a line, each line starting with `prefix`.
+    pub fn print(&self, prefix: &str) {
+        let regs = [
+            ("rip", self.rip),
+            ("rsp", self.rsp),
+            ("rflags", self.rflags),
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("cs", self.cs),
+            ("ss", self.ss),
+            ("ds", self.ds),
+            ("es", self.es),
+            ("vector", self.vector),
+            ("error", self.error),
+        ];
+        for line in regs.chunks(3) {
+            let mut s = String::from(prefix);
+            for (name, v) in line {
+                s += &format!(" {:>3}={:016x}", name, v);
+            }
+            println!("{}", s);
+        }
+    }
+}
+
+/// Saves everything on top of the CPU's interrupt frame plus vector and error code, calls
+/// `trap_dispatch` and returns through the frame, which the handler may have changed.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn trap_common() {
+    asm!(
+        "
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        xor eax, eax
+        mov ax, ds
+        push rax
+        mov ax, es
+        push rax
+        mov rbp, rsp
+        sub rsp, 512
+        and rsp, -16
+        fxsave64 [rsp]
+        cld
+        mov rdi, rbp
+        call trap_dispatch
+        fxrstor64 [rsp]
+        mov rsp, rbp
+    .global trap_exit
+    trap_exit:
+        pop rax
+        mov es, ax
+        pop rax
+        mov ds, ax
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        add rsp, 16
+        iretq
+    ",
+        options(noreturn)
+    );
+}
+/// Leaves the kernel through `frame`, usually to user mode. Restore the FPU state first.
+pub unsafe fn resume(frame: &TrapFrame) -> ! {
+    asm!("
+    cli
+    mov rsp, rdi
+    jmp trap_exit", in("rdi") frame as *const TrapFrame, options(noreturn));
+}
+
+macro_rules! entry {
+    ($name:ident, $vector:literal) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            asm!(
+                concat!("push 0\npush ", stringify!($vector), "\njmp trap_common"),
+                options(noreturn)
+            );
+        }
+    };
+    // the CPU already pushed an error code
+    ($name:ident, $vector:literal, error) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            asm!(
+                concat!("push ", stringify!($vector), "\njmp trap_common"),
+                options(noreturn)
+            );
+        }
+    };
+}
+entry!(breakpoint_entry, 3);
+entry!(invalid_opcode_entry, 6);
+entry!(gpe_entry, 13, error);
+entry!(page_fault_entry, 14, error);
+entry!(timer_entry, 32);
+entry!(keyboard_entry, 33);
+entry!(com2_entry, 35);
+entry!(com1_entry, 36);
+entry!(primary_ata_entry, 46);
+
+/// An entry stub as whatever handler type an IDT entry wants.
+pub unsafe fn handler<F>(entry: unsafe extern "C" fn()) -> F {
+    core::mem::transmute_copy(&entry)
+}
@@ -2,49 +2,217 @@ use core::usize;
 
 use crate::{
     drive::{gpt::GetGPTPartitions, RODev},
-    memory::map_to,
+    memory::aspace::{AddressSpace, AddressSpaceRef, Region},
     prelude::*,
 };
+use handle::{Handle, Object, RIGHT_MAP, RIGHT_RECV, RIGHT_SEND, RIGHT_TRANSFER};
 use kmacros::handle_read;
 use preempt::WakeType;
 use x86_64::{
+    structures::idt::PageFaultErrorCode,
     structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
     VirtAddr,
 };
-use xmas_elf::{self, program::Type};
 
-fn read_from_user<T>(ptr: *mut T) -> &'static T {
-    if ptr as u64 >= 0xFFFF800000000000 {
-        unsafe { ptr.as_ref().unwrap() }
-    } else {
-        panic!("Security violation: read attempted from {:p}", ptr);
+/// Lowest user address; the user half of the address space is the top one.
+pub const USER_BASE: u64 = 0xFFFF800000000000;
+/// Where mmap places anonymous memory, well above anything sbrk will reach.
+pub const MMAP_BASE: u64 = 0xFFFFA00000000000;
+pub const MMAP_END: u64 = 0xFFFFC00000000000;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const ARCH_SET_GS: u64 = 0x1001;
+pub const ARCH_SET_FS: u64 = 0x1002;
+pub const ARCH_GET_FS: u64 = 0x1003;
+pub const ARCH_GET_GS: u64 = 0x1004;
+fn prot_to_flags(prot: u64) -> SysResult<PageTableFlags> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SysError::EINVAL);
     }
+    // PROT_NONE pages stay present but kernel-only, so user accesses fault
+    let mut flags = PageTableFlags::PRESENT;
+    if prot != 0 {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    Ok(flags)
 }
-pub fn ensure_region_safe(ptr: *mut u8, len: usize) {
-    if (ptr as u64) < 0xFFFF800000000000 {
-        panic!("Security violation: read attempted from {:p}", ptr);
-    } else if (ptr as u64).overflowing_add(len as u64).0 < 0xFFFF800000000000 {
-        panic!("Security violation: read attempted from {:p}", ptr);
+fn page_round(len: u64) -> SysResult<u64> {
+    len.checked_add(4095)
+        .map(|l| l & !0xfff)
+        .ok_or(SysError::EINVAL)
+}
+// an (addr, len) pair naming whole pages inside the mmap area
+fn mmap_range(addr: u64, len: u64) -> SysResult<(u64, u64)> {
+    if addr & 0xfff != 0 {
+        return Err(SysError::EINVAL);
+    }
+    let end = addr
+        .checked_add(page_round(len)?)
+        .ok_or(SysError::EINVAL)?;
+    if addr < MMAP_BASE || end > MMAP_END {
+        return Err(SysError::EINVAL);
     }
+    Ok((addr, end))
 }
-fn user_gets(mut ptr: *mut u8, n: u64) -> String {
-    let mut s = vec![];
-    unsafe {
-        for _ in 0..n {
-            s.push(*read_from_user(ptr));
-            ptr = ptr.offset(1);
-        }
+// a timeout in ms from user space, -1 = wait forever, 0 = don't block
+fn timeout_arg(ms: u64) -> Option<u64> {
+    match ms {
+        u64::MAX => None,
+        ms => Some(interrupts::ms_to_ticks(ms)),
     }
-    String::from_utf8(s).unwrap()
 }
-pub struct Service {
-    pid: u64,
-    is_active: bool,
-    activate_pids: LinkedList<u64>,
+/// User stacks live here, each with an unmapped guard page below it.
+pub const STACK_AREA_BASE: u64 = 0xFFFFC00000000000;
+pub const STACK_AREA_END: u64 = 0xFFFFE00000000000;
+pub const USER_STACK_SIZE: u64 = 4096 * 32;
+/// Longest buffer `bindbuffer` copies in.
+pub const MAX_BUFFER_SIZE: u64 = 16 << 20;
+/// Longest string (service name, klog text) a syscall copies in.
+pub const MAX_STRING_SIZE: u64 = 64 << 10;
+/// Reserves a user stack, returning its top. Pages are mapped as they are touched.
+pub fn alloc_user_stack(a: &mut AddressSpace) -> SysResult<u64> {
+    let guard = a
+        .find_free(USER_STACK_SIZE + 4096, STACK_AREA_BASE, STACK_AREA_END)
+        .ok_or(SysError::ENOMEM)?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    let start = guard + 4096;
+    a.add_region(Region {
+        start,
+        len: USER_STACK_SIZE,
+        flags,
+    });
+    Ok(start + USER_STACK_SIZE)
+}
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+/// Lays out argc, argv, envp and the auxiliary vector below `top` like the SysV ABI wants,
+/// returning the initial stack pointer. The address space must be the active one.
+/// E2BIG if argv and envp take more than half of the initial stack, strings and pointers together.
+fn check_args(args: &ksvc::ExecArgs) -> SysResult<()> {
+    let strings: u64 = args
+        .argv
+        .iter()
+        .chain(args.envp.iter())
+        .map(|s| s.len() as u64 + 1)
+        .sum();
+    // argc, the pointers, two NULLs and six auxv pairs, plus alignment
+    let words = (args.argv.len() + args.envp.len()) as u64 + 15;
+    if strings + words * 8 + 16 > USER_STACK_SIZE / 2 {
+        return Err(SysError::E2BIG);
+    }
+    Ok(())
 }
+fn push_initial_stack(top: u64, args: &ksvc::ExecArgs, exe: &loader::LoadedElf) -> SysResult<u64> {
+    check_args(args)?;
+    let mut strings: Vec<u8> = vec![];
+    let mut offsets: Vec<u64> = vec![];
+    for s in args.argv.iter().chain(args.envp.iter()) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (top - strings.len() as u64) & !0x7;
+
+    let mut words: Vec<u64> = vec![args.argv.len() as u64];
+    let (argv, envp) = offsets.split_at(args.argv.len());
+    words.extend(argv.iter().map(|o| strings_start + o));
+    words.push(0);
+    words.extend(envp.iter().map(|o| strings_start + o));
+    words.push(0);
+    words.extend_from_slice(&[
+        AT_PHDR, exe.phdr, AT_PHENT, exe.phent, AT_PHNUM, exe.phnum, AT_PAGESZ, 4096, AT_ENTRY,
+        exe.entry, AT_NULL, 0,
+    ]);
+    let rsp = (strings_start - (words.len() as u64 * 8)) & !0xf;
 
-ezy_static! { SVC_MAP, spin::Mutex<BTreeMap<String, Service>>, spin::Mutex::new(BTreeMap::new()) }
-fn freebox1() {
+    let mut image: Vec<u8> = words.iter().flat_map(|w| w.to_ne_bytes().to_vec()).collect();
+    image.resize((strings_start - rsp) as usize, 0);
+    image.extend_from_slice(&strings);
+    copy_to_user(rsp, &image)?;
+    Ok(rsp)
+}
+/// Checks that every page of `[ptr, ptr + len)` is a user page (and writable, if `write`), either
+/// mapped or reserved. Reserved pages are left for the copy to fault in, copy-on-write pages that
+/// get written are copied now.
+pub fn check_user_range(ptr: u64, len: usize, write: bool) -> SysResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    if ptr < USER_BASE {
+        return Err(SysError::EFAULT);
+    }
+    let last = ptr.checked_add(len as u64 - 1).ok_or(SysError::EFAULT)?;
+    let mut page = ptr & !0xfff;
+    loop {
+        let flags = match crate::memory::get_flags_for(VirtAddr::new(page)) {
+            Some(f) if f.contains(PageTableFlags::PRESENT) => f,
+            _ => {
+                current_aspace()?
+                    .get()
+                    .region_for(page)
+                    .ok_or(SysError::EFAULT)?
+                    .flags
+            }
+        };
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return Err(SysError::EFAULT);
+        }
+        if write && !flags.contains(PageTableFlags::WRITABLE) {
+            if !flags.contains(crate::memory::allocator::COW_PAGE)
+                || !current_aspace()?.get().handle_cow(VirtAddr::new(page))
+            {
+                return Err(SysError::EFAULT);
+            }
+        }
+        if page == last & !0xfff {
+            return Ok(());
+        }
+        page += 4096;
+    }
+}
+pub fn copy_from_user(to: &mut [u8], from: u64) -> SysResult<()> {
+    check_user_range(from, to.len(), false)?;
+    unsafe {
+        accelmemcpy(to.as_mut_ptr(), from as *const u8, to.len());
+    }
+    Ok(())
+}
+pub fn copy_to_user(to: u64, from: &[u8]) -> SysResult<()> {
+    check_user_range(to, from.len(), true)?;
+    unsafe {
+        accelmemcpy(to as *mut u8, from.as_ptr(), from.len());
+    }
+    Ok(())
+}
+fn user_gets(ptr: u64, n: u64) -> SysResult<String> {
+    // check before allocating so a bogus length can't exhaust the heap
+    if n > MAX_STRING_SIZE {
+        return Err(SysError::E2BIG);
+    }
+    check_user_range(ptr, n as usize, false)?;
+    let mut s = vec![0; n as usize];
+    copy_from_user(&mut s, ptr)?;
+    String::from_utf8(s).map_err(|_| SysError::EINVAL)
+}
+/// The current process' address space; kernel tasks have none.
+pub fn current_aspace() -> SysResult<AddressSpaceRef> {
+    task().aspace.clone().ok_or(SysError::EFAULT)
+}
+pub fn freebox1() {
     match task().box1 {
         Some(s) => {
             // free it
@@ -56,7 +224,7 @@ fn freebox1() {
         None => {}
     };
 }
-fn freebox2() {
+pub fn freebox2() {
     match task().box2 {
         Some(s) => {
             // free it
@@ -68,32 +236,45 @@ fn freebox2() {
         None => {}
     };
 }
-pub fn syscall_handler(sysno: u64, arg1: u64, arg2: u64) -> u64 {
+// a kernel service failing its request also gets the error back in buf1
+fn ksvc_result(r: SysResult<()>) -> SysResult<u64> {
+    match r {
+        Ok(()) => Ok(0),
+        Err(e) => {
+            ksvc::reply(&ksvc::KSvcResult::Failure(e));
+            Err(e)
+        }
+    }
+}
+pub fn syscall_handler(sysno: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
     dprintln!(" ===> enter {} {:#x?}", task().pid, sysno);
-    let v = match sysno {
+    task().syscalls += 1;
+    let v = match do_syscall(sysno, arg1, arg2, arg3) {
+        Ok(v) => v,
+        Err(e) => {
+            dprintln!(" <=== error {:?}", e);
+            e.as_ret()
+        }
+    };
+    dprintln!(" <=== exit {}", task().pid);
+    v
+}
+fn do_syscall(sysno: u64, arg1: u64, arg2: u64, arg3: u64) -> SysResult<u64> {
+    Ok(match sysno {
         0 => {
             /* sys_exit */
-            loop {
-                preempt::yield_task();
-            }
+            preempt::exit_task(arg1);
         }
         1 => {
             /* sys_bindbuffer */
-            match task().box1 {
-                Some(s) => {
-                    // free it
-                    task().box1 = None;
-                    unsafe {
-                        Box::from_raw(s as *const [u8] as *mut [u8]);
-                    }
-                }
-                None => {}
-            };
+            if arg2 > MAX_BUFFER_SIZE {
+                return Err(SysError::E2BIG);
+            }
+            check_user_range(arg1, arg2 as usize, false)?;
             let mut p = vec![];
             p.resize(arg2 as usize, 0);
-            unsafe {
-                accelmemcpy(p.as_mut_ptr(), arg1 as *const u8, arg2 as usize);
-            }
+            copy_from_user(&mut p, arg1)?;
+            freebox1();
             task().box1 = Some(Box::leak(p.into_boxed_slice()));
             0
         }
@@ -108,9 +289,7 @@ pub fn syscall_handler(sysno: u64, arg1: u64, arg2: u64) -> u64 {
             /* sys_readbuffer */
             match task().box1 {
                 Some(s) => {
-                    unsafe {
-                        accelmemcpy(arg1 as *mut u8, s.as_ptr(), s.len());
-                    };
+                    copy_to_user(arg1, s)?;
                     s.len() as u64
                 }
                 None => 0,
@@ -126,174 +305,311 @@ pub fn syscall_handler(sysno: u64, arg1: u64, arg2: u64) -> u64 {
         }
         5 => {
             /* sys_send */
-            let target = user_gets(arg1 as *mut u8, arg2);
+            let target = user_gets(arg1, arg2)?;
             if target == "kfs" {
-                ksvc::dofs();
-                dprintln!(" <=== exit {}", task().pid);
-                return 0;
+                return ksvc_result(ksvc::dofs());
             }
-            x86_64::instructions::interrupts::without_interrupts(|| {
-                if ksvc::KSVC_TABLE.contains_key(&target) {
-                    ksvc::KSVC_TABLE.get().get(&target).unwrap()();
-                    dprintln!(" <=== exit {}", task().pid);
-                    return;
-                }
-                let mut svclock = SVC_MAP.lock();
-                let p = svclock.get_mut(&target).unwrap();
-                for r in preempt::TASK_QUEUE.get().iter_mut() {
-                    if p.pid == r.pid {
-                        while !p.is_active {
-                            p.activate_pids.push_back(task().pid);
-                            task().needs_wake = true;
-                            preempt::yield_task();
-                            assert_eq!(
-                                task().wakeop,
-                                Some(preempt::Wakeop {
-                                    wake_type: preempt::WakeType::WakeServerReady,
-                                    waker: p.pid
-                                })
-                            );
-                        }
-                        p.activate_pids.push_back(task().pid);
-                        task().needs_wake = true;
-                        preempt::yield_task();
-                        assert_eq!(
-                            task().wakeop,
-                            Some(preempt::Wakeop {
-                                wake_type: preempt::WakeType::WakeConnection,
-                                waker: p.pid
-                            })
-                        );
-                        r.box1 = task().box1;
-                        r.box2 = task().box2;
-                        task().needs_wake = true;
-                        preempt::yield_task();
-                        assert_eq!(
-                            task().wakeop,
-                            Some(preempt::Wakeop {
-                                wake_type: preempt::WakeType::WakeResponded,
-                                waker: p.pid
-                            })
-                        );
-                        freebox1();
-                        freebox2();
-                        task().box1 = r.box1;
-                        task().box1 = r.box2;
-                        r.box1 = None;
-                        r.box2 = None;
-                    }
-                }
-            });
-            0
+            if ksvc::KSVC_TABLE.contains_key(&target) {
+                return ksvc_result(ksvc::KSVC_TABLE.get().get(&target).unwrap()());
+            }
+            ipc::call(ipc::named(&target, RIGHT_SEND)?, timeout_arg(arg3))?
         }
         6 => {
             /* sys_listen */
-            let name = user_gets(arg1 as *mut u8, arg2);
-            SVC_MAP.lock().insert(
-                name,
-                Service {
-                    pid: task().pid,
-                    activate_pids: LinkedList::new(),
-                    is_active: false,
-                },
-            );
-            0
+            let id = ipc::listen(user_gets(arg1, arg2)?)?;
+            handle::install(
+                task().pid,
+                Handle::new(Object::Channel(id), RIGHT_SEND | RIGHT_RECV | RIGHT_TRANSFER),
+            )
         }
         7 => {
             /* sys_accept */
-            let nejm = user_gets(arg1 as *mut u8, arg2);
-            let mut ent = SVC_MAP.lock();
-            let svc = ent.get_mut(&nejm).unwrap();
-            let q = svc.activate_pids.pop_front();
-            if q != None {
-                let q = q.unwrap();
-                for r in preempt::TASK_QUEUE.get().iter_mut() {
-                    if q == r.pid {
-                        assert!(r.needs_wake);
-                        r.wakeop = Some(preempt::Wakeop {
-                            wake_type: preempt::WakeType::WakeServerReady,
-                            waker: task().pid,
-                        });
-                        r.needs_wake = false;
-                        break;
-                    }
-                }
-            }
-            task().needs_wake = true;
-            preempt::yield_task();
-            assert_eq!(task().wakeop.unwrap().wake_type, WakeType::WakeConnection);
-
-            task().currently_responding_to = task().wakeop.unwrap().waker;
-            0
+            let id = ipc::named(&user_gets(arg1, arg2)?, RIGHT_RECV)?;
+            ipc::accept(id, timeout_arg(arg3))?
         }
         8 => {
             /* sys_exec */
-            x86_64::instructions::interrupts::without_interrupts(|| {
-                let l = task().box1;
-                do_exec(l.unwrap());
-            });
-            0
+            x86_64::instructions::interrupts::without_interrupts(|| do_exec())?
         }
         9 => {
             /* sys_respond */
-            x86_64::instructions::interrupts::without_interrupts(|| {
-                let resp = task().currently_responding_to;
-
-                for r in preempt::TASK_QUEUE.get().iter_mut() {
-                    if resp == r.pid {
-                        assert!(r.needs_wake);
-                        r.needs_wake = false;
-                        r.wakeop = Some(preempt::Wakeop {
-                            wake_type: preempt::WakeType::WakeResponded,
-                            waker: task().pid,
-                        });
-                        sched_yield();
-                        assert_eq!(task().box1, None);
-                        assert_eq!(task().box2, None);
-                    }
-                }
-            });
+            ipc::reply(task().currently_responding_to)?;
             0
         }
         10 => {
             /* sys_klog */
-            print!("{}", user_gets(arg1 as *mut u8, arg2));
+            print!("{}", user_gets(arg1, arg2)?);
 
             0
         }
         11 => {
             /* sys_sbrk */
             let len = arg1;
-            let oldbrk = task().program_break;
-            let newbrk = ((oldbrk + len + 4095) / 4096) * 4096;
-            task().program_break = newbrk;
-            for i in 0..(((newbrk - oldbrk) / 4096) + 1) {
-                let pageaddr = oldbrk + i * 4096;
-
-                let data = crate::memory::mpage();
-                // pages.push(data);
-                if pageaddr < 0xFFFF800000000000 {
-                    panic!("Invalid target for sbrk! {:#x?}", pageaddr);
+            let aspace = current_aspace()?;
+            let a = aspace.get();
+            let oldbrk = a.program_break;
+            let newbrk = match oldbrk.checked_add(len).and_then(|b| b.checked_add(4095)) {
+                Some(b) => (b / 4096) * 4096,
+                None => return Err(SysError::ENOMEM),
+            };
+            if newbrk > MMAP_BASE {
+                return Err(SysError::ENOMEM);
+            }
+            a.program_break = newbrk;
+            // only reserve the range, the pages are mapped on first touch
+            if newbrk > oldbrk {
+                a.add_region(Region {
+                    start: oldbrk,
+                    len: newbrk - oldbrk,
+                    flags: PageTableFlags::PRESENT
+                        | PageTableFlags::USER_ACCESSIBLE
+                        | PageTableFlags::WRITABLE,
+                });
+            }
+            newbrk
+        }
+        12 => {
+            /* sys_wait */
+            let (pid, code) = preempt::wait_child(arg1).ok_or(SysError::ECHILD)?;
+            freebox1();
+            // exit codes can look like -errno, so only the pid goes in rax
+            task().box1 = Some(postcard::to_allocvec(&(pid, code)).unwrap().leak());
+            pid
+        }
+        13 => {
+            /* sys_mmap */
+            let len = page_round(arg1)?;
+            if len == 0 {
+                return Err(SysError::EINVAL);
+            }
+            let flags = prot_to_flags(arg2)?;
+            let aspace = current_aspace()?;
+            let a = aspace.get();
+            let start = a
+                .find_free(len, MMAP_BASE, MMAP_END)
+                .ok_or(SysError::ENOMEM)?;
+            a.add_region(Region { start, len, flags });
+            start
+        }
+        14 => {
+            /* sys_munmap */
+            let (start, end) = mmap_range(arg1, arg2)?;
+            let aspace = current_aspace()?;
+            let a = aspace.get();
+            for r in a.take_range(start, end) {
+                for page in (r.start..r.end()).step_by(4096) {
+                    a.unmap(VirtAddr::new(page));
                 }
-                map_to(
-                    VirtAddr::from_ptr(data),
-                    VirtAddr::new(pageaddr),
-                    PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
-                );
-                preempt::yield_task();
             }
-            dprintln!(" <=== exit {}", task().pid);
-            return newbrk;
+            0
         }
-        _ => (-1 as i64) as u64,
-    };
-
-    dprintln!(" <=== exit {}", task().pid);
-    v
-}
-#[no_mangle]
-unsafe extern "C" fn syscall_trampoline_rust(sysno: u64, arg1: u64, arg2: u64) -> u64 {
-    syscall_handler(sysno, arg1, arg2)
+        15 => {
+            /* sys_mprotect */
+            let (start, end) = mmap_range(arg1, arg2)?;
+            let flags = prot_to_flags(arg3)?;
+            let aspace = current_aspace()?;
+            let a = aspace.get();
+            if !a.covers(start, end) {
+                return Err(SysError::ENOMEM);
+            }
+            for r in a.take_range(start, end) {
+                // shared memory stays shared
+                let flags = flags | (r.flags & crate::memory::allocator::SHARED_PAGE);
+                a.add_region(Region { flags, ..r });
+                for page in (r.start..r.end()).step_by(4096) {
+                    a.update_flags(VirtAddr::new(page), flags);
+                }
+            }
+            0
+        }
+        16 => {
+            /* sys_fork */
+            do_fork()?
+        }
+        17 => {
+            /* sys_thread_spawn */
+            do_thread_spawn(arg1, arg2)?
+        }
+        18 => {
+            /* sys_thread_exit */
+            if task().ustack != 0 {
+                free_user_stack(current_aspace()?.get(), task().ustack);
+            }
+            preempt::exit_thread(arg1);
+        }
+        19 => {
+            /* sys_thread_join */
+            preempt::join_thread(arg1).ok_or(SysError::ESRCH)?
+        }
+        20 => {
+            /* sys_futex_wait */
+            futex::wait(arg1, arg2 as u32, timeout_arg(arg3))?
+        }
+        21 => {
+            /* sys_futex_wake */
+            futex::wake(arg1, arg2)?
+        }
+        22 => {
+            /* sys_arch_prctl */
+            match arg1 {
+                ARCH_SET_FS | ARCH_SET_GS => {
+                    let base = VirtAddr::try_new(arg2).map_err(|_| SysError::EINVAL)?;
+                    if arg1 == ARCH_SET_FS {
+                        preempt::set_fs_base(base);
+                    } else {
+                        preempt::set_gs_base(base);
+                    }
+                }
+                ARCH_GET_FS => copy_to_user(arg2, &task().fs_base.to_ne_bytes())?,
+                ARCH_GET_GS => copy_to_user(arg2, &task().gs_base.to_ne_bytes())?,
+                _ => return Err(SysError::EINVAL),
+            }
+            0
+        }
+        23 => {
+            /* sys_shm_create */
+            let len = page_round(arg1)?;
+            if len == 0 {
+                return Err(SysError::EINVAL);
+            }
+            let id = memory::shm::create(len / 4096)?;
+            handle::install(
+                task().pid,
+                Handle::new(Object::Shm(id), RIGHT_MAP | RIGHT_TRANSFER),
+            )
+        }
+        24 => {
+            /* sys_shm_map */
+            let flags = prot_to_flags(arg2)?;
+            let id = handle::shm(arg1, RIGHT_MAP)?;
+            memory::shm::map(id, current_aspace()?.get(), flags)?
+        }
+        25 => {
+            /* sys_shm_grant */
+            handle::grant(arg1, arg2)?
+        }
+        26 => {
+            /* sys_shm_close */
+            handle::shm(arg1, 0)?;
+            handle::close(arg1)?;
+            0
+        }
+        27 => {
+            /* sys_send_async */
+            let id = ipc::named(&user_gets(arg1, arg2)?, RIGHT_SEND)?;
+            x86_64::instructions::interrupts::without_interrupts(|| ipc::send(id, true, None))?
+        }
+        28 => {
+            /* sys_reply */
+            ipc::reply(arg1)?;
+            0
+        }
+        29 => {
+            /* sys_recv_reply */
+            ipc::recv_reply()?
+        }
+        30 => {
+            /* sys_poll */
+            if arg2 > ipc::POLL_MAX {
+                return Err(SysError::EINVAL);
+            }
+            let mut raw = vec![0u8; arg2 as usize * 8];
+            copy_from_user(&mut raw, arg1)?;
+            let ids = raw
+                .chunks(8)
+                .map(|c| u64::from_ne_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+                .map(|h| match h {
+                    0 => Ok(0),
+                    h => handle::channel(h, RIGHT_RECV),
+                })
+                .collect::<SysResult<Vec<u64>>>()?;
+            ipc::poll(&ids, timeout_arg(arg3))?
+        }
+        31 => {
+            /* sys_unlisten */
+            ipc::unlisten(&user_gets(arg1, arg2)?)?;
+            0
+        }
+        32 => {
+            /* sys_lookup */
+            let name = user_gets(arg1, arg2)?;
+            let id = match arg3 {
+                0 => ipc::lookup(&name, false, None)?,
+                u64::MAX => ipc::lookup(&name, true, None)?,
+                ms => ipc::lookup(&name, true, Some(interrupts::ms_to_ticks(ms)))?,
+            };
+            handle::install(
+                task().pid,
+                Handle::new(Object::Channel(id), RIGHT_SEND | RIGHT_TRANSFER),
+            )
+        }
+        33 => {
+            /* sys_channel_create */
+            handle::install(
+                task().pid,
+                Handle::new(
+                    Object::Channel(ipc::create_channel()),
+                    RIGHT_SEND | RIGHT_RECV | RIGHT_TRANSFER,
+                ),
+            )
+        }
+        34 => {
+            /* sys_send_handle */
+            ipc::call(handle::channel(arg1, RIGHT_SEND)?, timeout_arg(arg2))?
+        }
+        35 => {
+            /* sys_accept_handle */
+            ipc::accept(handle::channel(arg1, RIGHT_RECV)?, timeout_arg(arg2))?
+        }
+        36 => {
+            /* sys_send_async_handle */
+            let id = handle::channel(arg1, RIGHT_SEND)?;
+            x86_64::instructions::interrupts::without_interrupts(|| ipc::send(id, true, None))?
+        }
+        37 => {
+            /* sys_handle_dup */
+            handle::dup(arg1, arg2)?
+        }
+        38 => {
+            /* sys_handle_close */
+            handle::close(arg1)?;
+            0
+        }
+        39 => {
+            /* sys_handle_attach */
+            handle::attach(arg1)?;
+            0
+        }
+        40 => {
+            /* sys_handle_take */
+            handle::take_received()?
+        }
+        41 => {
+            /* sys_process_handle */
+            handle::process(arg1)?
+        }
+        42 => {
+            /* sys_nice */
+            let nice = arg2 as i64;
+            if nice < preempt::NICE_MIN as i64 || nice > preempt::NICE_MAX as i64 {
+                return Err(SysError::EINVAL);
+            }
+            let t = if arg1 == 0 || arg1 == task().tid {
+                task()
+            } else {
+                preempt::queued_task(arg1)
+                    .filter(|t| t.pid == task().pid && t.aspace.is_some())
+                    .ok_or(SysError::ESRCH)?
+            };
+            // like on unix, only root gets to ask for more cpu
+            if (nice as i8) < t.nice && task().uid != 0 {
+                return Err(SysError::EPERM);
+            }
+            preempt::set_nice(t, nice as i8);
+            0
+        }
+        _ => return Err(SysError::ENOSYS),
+    })
 }
 extern "C" {
     static mut RSP_PTR: u64;
@@ -306,54 +622,42 @@ pub fn set_rsp_ptr(va: VirtAddr) {
         RSP_PTR = va.as_u64();
     }
 }
+const RSP_PTR_STACK_SIZE: usize = 4096 * 5;
 pub fn alloc_rsp_ptr(stack_name: String) -> VirtAddr {
-    const STACK_SIZE: usize = 4096 * 5;
-    let stack_start = VirtAddr::from_ptr(crate::memory::malloc(STACK_SIZE));
-    let stack_end = stack_start + STACK_SIZE;
-    stack_canaries::add_canary(stack_start, stack_name, STACK_SIZE as u64);
+    let stack_start = VirtAddr::from_ptr(crate::memory::malloc(RSP_PTR_STACK_SIZE));
+    let stack_end = stack_start + RSP_PTR_STACK_SIZE;
+    stack_canaries::add_canary(stack_start, stack_name, RSP_PTR_STACK_SIZE as u64);
     stack_end
 }
+pub fn free_rsp_ptr(stack_end: VirtAddr) {
+    let stack_start = stack_end - RSP_PTR_STACK_SIZE;
+    stack_canaries::remove_canary(stack_start);
+    crate::memory::free(stack_start.as_mut_ptr());
+}
 pub fn init_rsp_ptr(stack_name: String) {
     set_rsp_ptr(alloc_rsp_ptr(stack_name));
 }
+/// Makes the same frame an interrupt from user mode would, on the syscall stack, and goes
+/// through `trap::trap_common` like one. SFMask keeps interrupts off until the frame is saved.
 #[naked]
 pub unsafe extern "C" fn new_syscall_trampoline() {
     asm!(
         "
-        cli
-        push rcx
-        push r11
-        push rbp
-        mov rbp, rsp
+        mov [SYSCALL_USER_RSP], rsp
         mov rsp, [RSP_PTR]
-        push rbp
-        push rbx
+        push 0x1b
+        push qword ptr [SYSCALL_USER_RSP]
+        push r11
+        push 0x23
         push rcx
-        push rdx
-        push r12
-        push r13
-        push r14
-        push r15
-        mov rbp, rsp
-        call syscall_trampoline_rust
-        mov rsp, rbp
-        pop r15
-        pop r14
-        pop r13
-        pop r12
-        pop rdx
-        pop rcx
-        pop rbx
-        pop rbp
-        mov rsp, rbp
-        pop rbp
-        pop r11
-        pop rcx
-    just_a_brk:
-        sysretq
+        push 0
+        push 0x80
+        jmp trap_common
     .global RSP_PTR
     RSP_PTR:
         .space 0x8, 0x00
+    SYSCALL_USER_RSP:
+        .space 0x8, 0x00
     ",
         options(noreturn)
     );
@@ -382,137 +686,215 @@ pub fn mkpid() -> u64 {
 pub fn getpid() -> u64 {
     task().pid
 }
-#[handle_read]
-pub fn readfs(path: &str) -> &[u8] {
+#[handle_read(optional)]
+pub fn try_readfs(path: &str) -> Option<&'static [u8]> {
     panic!("asds");
 }
+pub fn readfs(path: &str) -> &'static [u8] {
+    match try_readfs(path) {
+        Some(data) => data,
+        None => panic!("File not found: {}", path),
+    }
+}
 
 pub fn loaduser() {
-    init_rsp_ptr("syscall-stack:/bin/init".to_string());
     let loaded_init = readfs("/bin/init");
-    let mut pages: Vec<*mut u8> = vec![];
-    let exe = xmas_elf::ElfFile::new(&loaded_init).unwrap();
-    let mut program_break: u64 = 0xFFFF800000000000;
-    for ph in exe.program_iter() {
-        if ph.get_type().unwrap() == Type::Load {
-            let mut flags = PageTableFlags::NO_EXECUTE | PageTableFlags::PRESENT;
-            // if ph.flags().is_read() {
-            flags |= PageTableFlags::USER_ACCESSIBLE;
-            // }
-            // if ph.flags().is_execute() {
-            flags ^= PageTableFlags::NO_EXECUTE;
-            // }
-            // if ph.flags().is_write() {
-            flags |= PageTableFlags::WRITABLE;
-            // }
-            let page_count = (ph.file_size() + 4095 + (ph.virtual_addr() % 4096)) / 4096;
-            for i in 0..page_count {
-                let data = crate::memory::mpage();
-                pages.push(data);
-                if ph.virtual_addr() + (i * 4096) < 0xFFFF800000000000 {
-                    panic!("Invalid target for ELF loader!");
-                }
-                map_to(
-                    VirtAddr::from_ptr(data),
-                    VirtAddr::new(ph.virtual_addr() + (i * 4096)),
-                    flags,
-                );
-            }
-            let maybe_new_program_break = ph.virtual_addr() + (page_count * 4096);
-            program_break = if maybe_new_program_break < program_break {
-                program_break
-            } else {
-                maybe_new_program_break
-            };
-            unsafe {
-                accelmemcpy(
-                    ph.virtual_addr() as *mut u8,
-                    loaded_init.as_ptr().offset(ph.offset() as isize),
-                    ph.file_size() as usize,
-                );
-            }
-        }
+    if let Err(e) = loader::validate(loaded_init) {
+        println!("Cannot load /bin/init: {:?}", e);
+        return;
     }
-    // now initialize all the necessary fields.
-
-    // To free just fpage() all of the `pages`
+    init_rsp_ptr("syscall-stack:/bin/init".to_string());
     task().pid = mkpid();
-    task().program_break = ((program_break + 4095) / 4096) * 4096;
+    preempt::set_tid(task().pid);
+    let args = ksvc::ExecArgs {
+        argv: vec!["/bin/init".to_string()],
+        envp: vec![],
+    };
+    task().box1 = Some(postcard::to_allocvec(&args).unwrap().leak());
+    let aspace = AddressSpace::new_ref();
+    aspace.get().activate();
+    task().aspace = Some(aspace.clone());
+    task().exe = Some(("/bin/init".to_string(), loaded_init));
+    let exe = loader::load(loaded_init, aspace.get()).unwrap();
+    let top = alloc_user_stack(aspace.get()).unwrap();
+    let rsp = push_initial_stack(top, &args, &exe).unwrap();
+    // now initialize all the necessary fields.
+    aspace.get().program_break = exe.program_break;
+    preempt::set_fs_base(VirtAddr::new(exe.thread_pointer));
     unsafe {
-        jump_user(exe.header.pt2.entry_point());
+        jump_user(exe.entry, rsp, 0);
     }
 }
 
-pub fn do_exec(kernel: &[u8]) {
-    let slice = kernel.to_vec();
-    let ve = task().box2.take();
+/// Starts the program named by buf1 with the `ksvc::ExecArgs` blob in buf2 (defaults to just argv[0]).
+/// Returns the new pid.
+pub fn do_exec() -> SysResult<u64> {
+    let path = match task().box1 {
+        Some(b) => String::from_utf8(b.to_vec()).map_err(|_| SysError::EINVAL)?,
+        None => return Err(SysError::EINVAL),
+    };
+    let slice = try_readfs(&path).ok_or(SysError::ENOENT)?;
+    loader::validate(slice)?;
+    let args: ksvc::ExecArgs = match task().box2 {
+        Some(b) => postcard::from_bytes(b).map_err(|_| SysError::EINVAL)?,
+        None => ksvc::ExecArgs {
+            argv: vec![path.clone()],
+            envp: vec![],
+        },
+    };
+    // fail exec itself rather than the child
+    check_args(&args)?;
+    let ve = postcard::to_allocvec(&args).unwrap();
     freebox1();
     freebox2();
-    let path = String::from_utf8(slice).unwrap();
-    let path2 = path.clone();
-    preempt::task_alloc(
+    let pid = mkpid();
+    let stknm = format!("syscall-stack:{}", path);
+    preempt::process_alloc(
         move || unsafe {
             x86_64::instructions::interrupts::disable();
-            let slice = readfs(&path);
-
-            let ncr3 = main::forkp();
-            let mut pages: Vec<*mut u8> = vec![];
-            let exe = xmas_elf::ElfFile::new(&slice).unwrap();
-            let mut program_break: u64 = 0xFFFF800000000000;
-            for ph in exe.program_iter() {
-                let mut flags = PageTableFlags::NO_EXECUTE | PageTableFlags::PRESENT;
-                flags |= PageTableFlags::USER_ACCESSIBLE;
-                flags ^= PageTableFlags::NO_EXECUTE;
-                flags |= PageTableFlags::WRITABLE;
-                let page_count = (ph.file_size() + 4095) / 4096;
-                for i in 0..page_count {
-                    let data = crate::memory::mpage();
-                    pages.push(data);
-                    if ph.virtual_addr() + (i * 4096) < 0xFFFF800000000000 {
-                        panic!("Invalid target for ELF loader!");
-                    }
-                    map_to(
-                        VirtAddr::from_ptr(data),
-                        VirtAddr::new(ph.virtual_addr() + (i * 4096)),
-                        flags,
-                    );
-                }
-                let maybe_new_program_break = ph.virtual_addr() + (page_count * 4096);
-                program_break = if maybe_new_program_break < program_break {
-                    program_break
-                } else {
-                    maybe_new_program_break
-                };
-                accelmemcpy(
-                    ph.virtual_addr() as *mut u8,
-                    slice.as_ptr().offset(ph.offset() as isize),
-                    ph.file_size() as usize,
-                );
-            }
-            x86_64::registers::control::Cr3::write(ncr3.0, ncr3.1);
-            task().box1 = ve;
-            task().pid = mkpid();
-            task().program_break = program_break;
+            let aspace = AddressSpace::new_ref();
+            aspace.get().activate();
+            task().aspace = Some(aspace.clone());
+            task().exe = Some((path, slice));
+            let (exe, rsp) = match loader::load(slice, aspace.get()).and_then(|exe| {
+                let top = alloc_user_stack(aspace.get())?;
+                let rsp = push_initial_stack(top, &args, &exe)?;
+                Ok((exe, rsp))
+            }) {
+                Ok(r) => r,
+                Err(e) => preempt::exit_task(e.as_ret()),
+            };
+            task().box1 = Some(ve.leak());
+            aspace.get().program_break = exe.program_break;
+            preempt::set_fs_base(VirtAddr::new(exe.thread_pointer));
             x86_64::instructions::interrupts::enable();
-            jump_user(exe.header.pt2.entry_point());
+            jump_user(exe.entry, rsp, 0);
         },
-        format!("syscall-stack:{}", path2.clone()),
+        stknm,
+        pid,
+        task().pid,
     );
+    Ok(pid)
 }
 
-unsafe fn jump_user(addr: u64) {
-    asm!("
-    mov ds,ax
-    mov es,ax 
-    mov fs,ax 
-    mov gs,ax
+/// Tries to resolve a page fault on user memory of the current task: maps reserved pages on
+/// first touch and copies copy-on-write pages. Returns false if the access really was bad.
+pub fn handle_user_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if addr.as_u64() < USER_BASE {
+        return false;
+    }
+    let aspace = match &task().aspace {
+        Some(a) => a.clone(),
+        None => return false,
+    };
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return aspace.get().populate(addr);
+    }
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        return aspace.get().handle_cow(addr);
+    }
+    false
+}
+/// Duplicates the current process; the child resumes from this syscall with rax = 0.
+fn do_fork() -> SysResult<u64> {
+    let aspace = current_aspace()?.get().fork();
+    let regs = task().user_regs().expect("fork outside of a syscall");
+    // from the real frame: the FPU state sits below it on the syscall stack, not below a copy
+    let fpu = regs.fpu().clone();
+    let mut frame = *regs;
+    frame.rax = 0;
+    let pid = mkpid();
+    let exe = task().exe.clone();
+    let (fs_base, gs_base) = (task().fs_base, task().gs_base);
+    let ppid = task().pid;
+    // the child must not run before it has its address space
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        preempt::process_alloc(
+            move || unsafe {
+                task().exe = exe;
+                preempt::set_fs_base(VirtAddr::new(fs_base));
+                preempt::set_gs_base(VirtAddr::new(gs_base));
+                fpu.restore();
+                trap::resume(&frame);
+            },
+            format!("syscall-stack:fork:{}", pid),
+            pid,
+            ppid,
+        );
+        let child = preempt::queued_task(pid).unwrap();
+        child.aspace = Some(alloc::sync::Arc::new(Mutex::new(aspace)));
+        child.uid = task().uid;
+        crate::handle::fork(ppid, pid);
+    });
+    Ok(pid)
+}
+/// Starts a thread at `entry(arg)` on a fresh user stack, returning its tid.
+/// There is no return address, a thread has to end with thread_exit.
+fn do_thread_spawn(entry: u64, arg: u64) -> SysResult<u64> {
+    if entry < USER_BASE {
+        return Err(SysError::EFAULT);
+    }
+    let top = alloc_user_stack(current_aspace()?.get())?;
+    let tid = mkpid();
+    preempt::thread_alloc(
+        move || unsafe {
+            x86_64::instructions::interrupts::disable();
+            task().ustack = top;
+            // like after a call: rsp + 8 is 16-byte aligned
+            jump_user(entry, top - 8, arg);
+        },
+        format!("syscall-stack:thread:{}", tid),
+        tid,
+    );
+    Ok(tid)
+}
+/// Unmaps the user stack ending at `top`.
+fn free_user_stack(a: &mut AddressSpace, top: u64) {
+    for r in a.take_range(top - USER_STACK_SIZE, top) {
+        for page in (r.start..r.end()).step_by(4096) {
+            a.unmap(VirtAddr::new(page));
+        }
+    }
+}
+/// Exit codes of tasks killed by a CPU exception, the way a shell reports signals.
+pub const EXIT_SEGV: u64 = 128 + 11;
+pub const EXIT_ILL: u64 = 128 + 4;
+/// Prints a crash report for the current task and kills it. For exceptions raised in ring 3.
+pub fn user_crash(
+    what: &str,
+    rip: u64,
+    addr: Option<u64>,
+    error: &dyn core::fmt::Debug,
+    code: u64,
+) -> ! {
+    let exe = task().exe.clone();
+    let (name, sym) = match exe {
+        Some((path, image)) => (path, loader::symbolize(image, rip)),
+        None => ("???".to_string(), None),
+    };
+    println!(
+        "[crash] pid {} ({}): {} at {:#x} <{}>",
+        task().pid,
+        name,
+        what,
+        rip,
+        sym.unwrap_or("???".to_string())
+    );
+    if let Some(addr) = addr {
+        println!("[crash]   address {:#x}", addr);
+    }
+    println!("[crash]   error {:?}", error);
+    if let Some(regs) = task().user_regs() {
+        regs.print("[crash]  ");
+    }
+    preempt::exit_task(code);
+}
 
-    mov rsi, rsp
-    push rax
-    push rsi
-    push 0x200
-    push rdx
-    push rdi
-    iretq", in("rdi") addr, in("ax") 0x1b, in("dx") 0x23, in("rsi") 0);
-    unreachable!();
+/// Enters user mode at `addr` with stack `stack` and rdi = `arg`, all other registers 0.
+unsafe fn jump_user(addr: u64, stack: u64, arg: u64) -> ! {
+    // nothing the kernel left in the xmm registers goes to user mode
+    fpu::FpuState::new().restore();
+    let mut frame = trap::TrapFrame::user(addr, stack);
+    frame.rdi = arg;
+    trap::resume(&frame);
 }
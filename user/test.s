@@ -23,14 +23,9 @@
 default rel
 section .text
 user:
-    mov rsp, stack_bottom
     lea rax, [hello_world]
     do_syscall sys_klog, rax, 0
     jmp $
 section .data
 hello_world:
     db "Hello, userland world!", 0
-align 8
-stack_top:
-    resb 4096
-stack_bottom:
\ No newline at end of file